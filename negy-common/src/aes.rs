@@ -1,58 +1,191 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use openssl::rand::rand_bytes;
-use openssl::symm::{decrypt, encrypt, Cipher};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
 
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+// Forward frames travel from the gateway to the exit, backward frames travel back.
+// Each side encrypts in one direction only, so both sides can share a key without reusing a nonce.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+impl Direction {
+    fn opposite(&self) -> Direction {
+        match &self {
+            Direction::Forward => Direction::Backward,
+            Direction::Backward => Direction::Forward,
+        }
+    }
+
+    fn nonce_prefix(&self) -> u8 {
+        match &self {
+            Direction::Forward => 1,
+            Direction::Backward => 2,
+        }
+    }
+}
+
+// AES-256-GCM with nonces derived from per-direction frame counters.
+// Modified, replayed or reordered frames fail authentication.
 #[derive(Debug)]
 pub struct Aes {
-    key: [u8; 32],
-    iv: [u8; 16],
+    key: [u8; KEY_LEN],
+    direction: Direction,
+    encrypt_counter: u64,
+    decrypt_counter: u64,
 }
 
 impl Aes {
-    pub fn new() -> Self {
-        let mut key = [0; 32];
-        let mut iv = [0; 16];
+    pub fn new(direction: Direction) -> Self {
+        let mut key = [0; KEY_LEN];
 
         rand_bytes(&mut key).unwrap();
-        rand_bytes(&mut iv).unwrap();
 
-        Aes { key, iv }
+        Aes::import(&key, direction)
     }
 
-    pub fn import(key_iv: &[u8; 48]) -> Self {
-        let mut key = [0; 32];
-        let mut iv = [0; 16];
-
-        key.copy_from_slice(&key_iv[..32]);
-        iv.copy_from_slice(&key_iv[32..]);
-
-        Aes { key, iv }
+    pub fn import(key: &[u8; KEY_LEN], direction: Direction) -> Self {
+        Aes {
+            key: *key,
+            direction,
+            encrypt_counter: 0,
+            decrypt_counter: 0,
+        }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        Ok(encrypt(
-            Cipher::aes_256_cbc(),
+    pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes::nonce(self.direction, self.encrypt_counter)?;
+        let mut tag = [0; TAG_LEN];
+
+        let mut encrypted = encrypt_aead(
+            Cipher::aes_256_gcm(),
             &self.key,
-            Some(&self.iv),
+            Some(&nonce),
+            &[],
             data,
-        )?)
+            &mut tag,
+        )?;
+        encrypted.extend_from_slice(&tag);
+
+        self.encrypt_counter += 1;
+
+        Ok(encrypted)
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        Ok(decrypt(
-            Cipher::aes_256_cbc(),
+    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < TAG_LEN {
+            bail!("encrypted frame is too short ({} bytes)", data.len())
+        }
+
+        let nonce = Aes::nonce(self.direction.opposite(), self.decrypt_counter)?;
+        let (encrypted, tag) = data.split_at(data.len() - TAG_LEN);
+
+        let decrypted = decrypt_aead(
+            Cipher::aes_256_gcm(),
             &self.key,
-            Some(&self.iv),
-            data,
-        )?)
+            Some(&nonce),
+            &[],
+            encrypted,
+            tag,
+        )
+        .map_err(|_| {
+            anyhow!(
+                "failed to authenticate frame #{}. it was modified, replayed or reordered.",
+                self.decrypt_counter
+            )
+        })?;
+
+        self.decrypt_counter += 1;
+
+        Ok(decrypted)
     }
 
-    pub fn get_key_iv(&self) -> [u8; 48] {
-        let mut key_iv = [0; 48];
+    pub fn get_key(&self) -> [u8; KEY_LEN] {
+        self.key
+    }
+
+    fn nonce(direction: Direction, counter: u64) -> Result<[u8; NONCE_LEN]> {
+        if counter == u64::MAX {
+            bail!("frame counter exhausted. the session must be renewed.")
+        }
+
+        let mut nonce = [0; NONCE_LEN];
+
+        nonce[0] = direction.nonce_prefix();
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+        Ok(nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_pair() -> (Aes, Aes) {
+        let gateway = Aes::new(Direction::Forward);
+        let node = Aes::import(&gateway.get_key(), Direction::Backward);
+
+        (gateway, node)
+    }
+
+    #[test]
+    fn aes_encrypt_decrypt_both_directions() {
+        let (mut gateway, mut node) = session_pair();
+
+        let forward = gateway.encrypt(b"forward").unwrap();
+        assert_eq!(node.decrypt(&forward).unwrap(), b"forward");
+
+        let backward = node.encrypt(b"backward").unwrap();
+        assert_eq!(gateway.decrypt(&backward).unwrap(), b"backward");
+    }
+
+    #[test]
+    fn aes_same_plaintext_differs() {
+        let (mut gateway, _) = session_pair();
+
+        let first = gateway.encrypt(&[0; 64]).unwrap();
+        let second = gateway.encrypt(&[0; 64]).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn aes_reject_modified_frame() {
+        let (mut gateway, mut node) = session_pair();
+
+        let mut encrypted = gateway.encrypt(&[0; 64]).unwrap();
+        encrypted[0] ^= 1;
+        assert!(node.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn aes_reject_replayed_frame() {
+        let (mut gateway, mut node) = session_pair();
+
+        let encrypted = gateway.encrypt(&[0; 64]).unwrap();
+        assert!(node.decrypt(&encrypted).is_ok());
+        assert!(node.decrypt(&encrypted).is_err());
+    }
+
+    #[test]
+    fn aes_reject_reordered_frames() {
+        let (mut gateway, mut node) = session_pair();
+
+        let _first = gateway.encrypt(&[0; 64]).unwrap();
+        let second = gateway.encrypt(&[1; 64]).unwrap();
+        assert!(node.decrypt(&second).is_err());
+    }
 
-        key_iv[..32].copy_from_slice(&self.key);
-        key_iv[32..].copy_from_slice(&self.iv);
+    #[test]
+    fn aes_reject_reflected_frame() {
+        let (mut gateway, _) = session_pair();
 
-        key_iv
+        let encrypted = gateway.encrypt(&[0; 64]).unwrap();
+        assert!(gateway.decrypt(&encrypted).is_err());
     }
 }
@@ -0,0 +1,230 @@
+use crate::protocol::ReplyStatus;
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::SocketAddr;
+
+pub const CELL_HEADER_LEN: usize = 5;
+pub const MAX_DATA_LEN: usize = 16 * 1024;
+
+const OPEN: u8 = 1;
+const OPENED: u8 = 2;
+const DATA: u8 = 3;
+const CLOSE: u8 = 4;
+const WINDOW: u8 = 5;
+
+// Messages between the gateway and the exit of a circuit, each sealed as one onion message.
+// Every cell belongs to a stream, so one circuit carries many client connections.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Cell {
+    Open {
+        stream_id: u32,
+        dist: String,
+    },
+    // `resolved` is the address the exit connected to
+    Opened {
+        stream_id: u32,
+        status: ReplyStatus,
+        message: String,
+        resolved: Option<SocketAddr>,
+    },
+    Data {
+        stream_id: u32,
+        data: Vec<u8>,
+    },
+    Close {
+        stream_id: u32,
+    },
+    Window {
+        stream_id: u32,
+        increment: u32,
+    },
+}
+
+impl Cell {
+    pub fn stream_id(&self) -> u32 {
+        match &self {
+            Cell::Open { stream_id, .. }
+            | Cell::Opened { stream_id, .. }
+            | Cell::Data { stream_id, .. }
+            | Cell::Close { stream_id }
+            | Cell::Window { stream_id, .. } => *stream_id,
+        }
+    }
+
+    pub fn encode(&self) -> Result<BytesMut> {
+        let mut bytes = BytesMut::new();
+
+        match &self {
+            Cell::Open { stream_id, dist } => {
+                bytes.put_u8(OPEN);
+                bytes.put_u32(*stream_id);
+                bytes.extend_from_slice(dist.as_bytes());
+            }
+            Cell::Opened {
+                stream_id,
+                status,
+                message,
+                resolved,
+            } => {
+                if message.len() > u16::MAX as usize {
+                    bail!("Protocol error. Opened message is too large.")
+                }
+
+                bytes.put_u8(OPENED);
+                bytes.put_u32(*stream_id);
+                bytes.put_u8(status.code());
+                bytes.put_u16(message.len() as u16);
+                bytes.extend_from_slice(message.as_bytes());
+
+                if let Some(resolved) = resolved {
+                    bytes.extend_from_slice(resolved.to_string().as_bytes());
+                }
+            }
+            Cell::Data { stream_id, data } => {
+                if data.len() > MAX_DATA_LEN {
+                    bail!(
+                        "data cell is too large ({} bytes, max {} bytes)",
+                        data.len(),
+                        MAX_DATA_LEN
+                    )
+                }
+
+                bytes.put_u8(DATA);
+                bytes.put_u32(*stream_id);
+                bytes.extend_from_slice(data);
+            }
+            Cell::Close { stream_id } => {
+                bytes.put_u8(CLOSE);
+                bytes.put_u32(*stream_id);
+            }
+            Cell::Window {
+                stream_id,
+                increment,
+            } => {
+                bytes.put_u8(WINDOW);
+                bytes.put_u32(*stream_id);
+                bytes.put_u32(*increment);
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn parse(mut bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < CELL_HEADER_LEN {
+            bail!("Protocol error. Cell is too short.")
+        }
+
+        let command = bytes.get_u8();
+        let stream_id = bytes.get_u32();
+
+        let cell = match command {
+            OPEN => Cell::Open {
+                stream_id,
+                dist: String::from_utf8(bytes.to_vec())?,
+            },
+            OPENED => {
+                if bytes.len() < 3 {
+                    bail!("Protocol error. Opened cell is too short.")
+                }
+
+                let status = ReplyStatus::parse(bytes.get_u8())?;
+                let message_len = bytes.get_u16() as usize;
+
+                if bytes.len() < message_len {
+                    bail!("Protocol error. Opened cell is too short.")
+                }
+
+                let message = String::from_utf8(bytes[..message_len].to_vec())?;
+                let resolved = &bytes[message_len..];
+
+                Cell::Opened {
+                    stream_id,
+                    status,
+                    message,
+                    resolved: if resolved.is_empty() {
+                        None
+                    } else {
+                        Some(std::str::from_utf8(resolved)?.parse()?)
+                    },
+                }
+            }
+            DATA => {
+                if bytes.len() > MAX_DATA_LEN {
+                    bail!("Protocol error. Data cell is too large.")
+                }
+
+                Cell::Data {
+                    stream_id,
+                    data: bytes.to_vec(),
+                }
+            }
+            CLOSE if bytes.is_empty() => Cell::Close { stream_id },
+            WINDOW if bytes.len() == 4 => Cell::Window {
+                stream_id,
+                increment: bytes.get_u32(),
+            },
+            _ => bail!("Protocol error. Invalid cell (command={}).", command),
+        };
+
+        Ok(cell)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_encode_parse() {
+        let cells = vec![
+            Cell::Open {
+                stream_id: 1,
+                dist: "127.0.0.1:80".to_owned(),
+            },
+            Cell::Opened {
+                stream_id: 1,
+                status: ReplyStatus::UpstreamConnectFailed,
+                message: "refused".to_owned(),
+                resolved: None,
+            },
+            Cell::Opened {
+                stream_id: 1,
+                status: ReplyStatus::Ok,
+                message: String::new(),
+                resolved: Some("[::1]:443".parse().unwrap()),
+            },
+            Cell::Data {
+                stream_id: 2,
+                data: vec![1; 100],
+            },
+            Cell::Close { stream_id: 3 },
+            Cell::Window {
+                stream_id: 4,
+                increment: 65536,
+            },
+        ];
+
+        for cell in cells {
+            assert_eq!(Cell::parse(&cell.encode().unwrap()).unwrap(), cell);
+        }
+    }
+
+    #[test]
+    fn cell_reject_too_large_data() {
+        let cell = Cell::Data {
+            stream_id: 1,
+            data: vec![0; MAX_DATA_LEN + 1],
+        };
+
+        assert!(cell.encode().is_err());
+    }
+
+    #[test]
+    fn cell_reject_malformed() {
+        assert!(Cell::parse(&[DATA, 0, 0]).is_err());
+        assert!(Cell::parse(&[0, 0, 0, 0, 1]).is_err());
+        assert!(Cell::parse(&[CLOSE, 0, 0, 0, 1, 0]).is_err());
+        assert!(Cell::parse(&[WINDOW, 0, 0, 0, 1, 0, 0]).is_err());
+    }
+}
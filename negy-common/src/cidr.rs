@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An IP range like `10.0.0.0/8` or `fc00::/7`. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        if prefix_len > max_prefix_len(&addr) {
+            bail!("invalid prefix length /{} for {}", prefix_len, addr)
+        }
+
+        Ok(Cidr {
+            addr: mask(&addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = canonical(ip);
+
+        self.addr.is_ipv4() == ip.is_ipv4() && mask(&ip, self.prefix_len) == self.addr
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Cidr::new(addr.parse()?, prefix_len.parse()?),
+            None => {
+                let addr: IpAddr = s.parse()?;
+                Cidr::new(addr, max_prefix_len(&addr))
+            }
+        }
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+// IPv4-mapped IPv6 addresses are matched as IPv4.
+pub fn canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => *ip,
+        },
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(addr: &IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(*v4)
+                .checked_shr(32 - prefix_len as u32)
+                .unwrap_or(0)
+                .checked_shl(32 - prefix_len as u32)
+                .unwrap_or(0);
+            IpAddr::V4(bits.into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(*v6)
+                .checked_shr(128 - prefix_len as u32)
+                .unwrap_or(0)
+                .checked_shl(128 - prefix_len as u32)
+                .unwrap_or(0);
+            IpAddr::V6(bits.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(cidr.contains(&"::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+
+        let cidr: Cidr = "fc00::/7".parse().unwrap();
+
+        assert!(cidr.contains(&"fd12::1".parse().unwrap()));
+        assert!(!cidr.contains(&"fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            "192.168.1.1/16".parse::<Cidr>().unwrap().to_string(),
+            "192.168.0.0/16"
+        );
+        assert_eq!("::1".parse::<Cidr>().unwrap().to_string(), "::1/128");
+        assert_eq!(
+            "0.0.0.0/0".parse::<Cidr>().unwrap().to_string(),
+            "0.0.0.0/0"
+        );
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::net::IpAddr;
+
+const MAX_HOST_LEN: usize = 255;
+
+// Where a stream goes. Hostnames stay unresolved until the exit, so the gateway never looks them up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Destination {
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if host.is_empty() || host.len() > MAX_HOST_LEN {
+            bail!("invalid destination host length {}", host.len())
+        }
+
+        if host
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "/?#@[]".contains(c))
+        {
+            bail!("invalid destination host {:?}", host)
+        }
+
+        if host.contains(':') && host.parse::<IpAddr>().is_err() {
+            bail!("invalid destination host {:?}", host)
+        }
+
+        if port == 0 {
+            bail!("invalid destination port 0")
+        }
+
+        Ok(Destination {
+            host: host.to_lowercase(),
+            port,
+        })
+    }
+
+    // `host:port` or `[ipv6]:port`
+    pub fn parse(authority: &str) -> Result<Self> {
+        match Destination::split(authority) {
+            (host, Some(port)) => Destination::new(host, port.parse()?),
+            (_, None) => bail!("port not found in destination {}", authority),
+        }
+    }
+
+    pub fn parse_with_default_port(authority: &str, default_port: u16) -> Result<Self> {
+        match Destination::split(authority) {
+            (host, Some(port)) => Destination::new(host, port.parse()?),
+            (host, None) => Destination::new(host, default_port),
+        }
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    fn split(authority: &str) -> (&str, Option<&str>) {
+        if let Some(rest) = authority.strip_prefix('[') {
+            return match rest.split_once(']') {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => (authority, None),
+            };
+        }
+
+        match authority.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (authority, None),
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_parse_hostname() {
+        let dist = Destination::parse("Example.com:443").unwrap();
+
+        assert_eq!(dist.host, "example.com");
+        assert_eq!(dist.port, 443);
+        assert_eq!(dist.ip(), None);
+        assert_eq!(dist.to_string(), "example.com:443");
+    }
+
+    #[test]
+    fn destination_parse_ip() {
+        let v4 = Destination::parse("127.0.0.1:80").unwrap();
+        assert_eq!(v4.ip(), Some("127.0.0.1".parse().unwrap()));
+
+        let v6 = Destination::parse("[::1]:8080").unwrap();
+        assert_eq!(v6.host, "::1");
+        assert_eq!(v6.to_string(), "[::1]:8080");
+    }
+
+    #[test]
+    fn destination_parse_default_port() {
+        assert_eq!(
+            Destination::parse_with_default_port("example.com", 80)
+                .unwrap()
+                .port,
+            80
+        );
+        assert_eq!(
+            Destination::parse_with_default_port("[::1]", 80)
+                .unwrap()
+                .to_string(),
+            "[::1]:80"
+        );
+        assert!(Destination::parse("example.com").is_err());
+    }
+
+    #[test]
+    fn destination_reject_invalid() {
+        assert!(Destination::parse(":80").is_err());
+        assert!(Destination::parse("example.com:0").is_err());
+        assert!(Destination::parse("example.com:65536").is_err());
+        assert!(Destination::parse("exa mple.com:80").is_err());
+        assert!(Destination::parse("::1:80").is_err());
+        assert!(Destination::parse("[not-ip:v6]:80").is_err());
+    }
+}
@@ -1,65 +1,100 @@
-use anyhow::Result;
-use bytes::BytesMut;
-use openssl::rand::rand_bytes;
+use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub struct EncryptedPayload {
     inner: BytesMut,
-    skip_offset: usize,
 }
 
-pub const DELIMITER_LEN: usize = 16;
+pub const FRAME_HEADER_LEN: usize = 4;
+pub const MAX_FRAME_LEN: usize = 1 << 16;
+
+impl Default for EncryptedPayload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl EncryptedPayload {
     pub fn new() -> Self {
         EncryptedPayload {
             inner: BytesMut::new(),
-            skip_offset: 0,
         }
     }
 
-    pub fn new_delimiter() -> [u8; DELIMITER_LEN] {
-        let mut delimiter = [0; DELIMITER_LEN];
-        rand_bytes(&mut delimiter).unwrap();
-        delimiter
+    pub fn encode(payload: &[u8]) -> Result<BytesMut> {
+        if payload.len() > MAX_FRAME_LEN {
+            bail!(
+                "frame is too large ({} bytes, max {} bytes)",
+                payload.len(),
+                MAX_FRAME_LEN
+            )
+        }
+
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.put_u32(payload.len() as u32);
+        frame.extend_from_slice(payload);
+
+        Ok(frame)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<BytesMut> {
+        let mut encrypted_payload = EncryptedPayload::new();
+        let mut payloads = encrypted_payload.read(payload)?;
+
+        if payloads.len() != 1 || !encrypted_payload.inner.is_empty() {
+            bail!("expected exactly one frame")
+        }
+
+        Ok(payloads.remove(0))
     }
 
-    pub fn read(
-        &mut self,
-        payload: &[u8],
-        delimiter: &[u8; DELIMITER_LEN],
-    ) -> Result<Vec<BytesMut>> {
+    pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<BytesMut> {
+        let frame_len = reader.read_u32().await? as usize;
+
+        if frame_len > MAX_FRAME_LEN {
+            bail!(
+                "frame is too large ({} bytes, max {} bytes)",
+                frame_len,
+                MAX_FRAME_LEN
+            )
+        }
+
+        let mut payload = BytesMut::zeroed(frame_len);
+        reader.read_exact(&mut payload).await?;
+
+        Ok(payload)
+    }
+
+    pub fn read(&mut self, payload: &[u8]) -> Result<Vec<BytesMut>> {
         self.inner.extend_from_slice(payload);
-        self.parse(delimiter)
+        self.parse()
     }
 
-    fn parse(&mut self, delimiter: &[u8; DELIMITER_LEN]) -> Result<Vec<BytesMut>> {
+    fn parse(&mut self) -> Result<Vec<BytesMut>> {
         let mut payloads: Vec<BytesMut> = Vec::new();
 
-        let delimiter_indices: Vec<usize> = self
-            .inner
-            .windows(DELIMITER_LEN)
-            .enumerate()
-            .skip_while(|(idx, _)| *idx < self.skip_offset)
-            .filter_map(
-                |(idx, bytes)| {
-                    if bytes == delimiter {
-                        Some(idx)
-                    } else {
-                        None
-                    }
-                },
-            )
-            .collect();
+        while self.inner.len() >= FRAME_HEADER_LEN {
+            let mut header = [0; FRAME_HEADER_LEN];
+            header.copy_from_slice(&self.inner[..FRAME_HEADER_LEN]);
 
-        let mut offset = 0;
+            let frame_len = u32::from_be_bytes(header) as usize;
 
-        for &idx in delimiter_indices.iter() {
-            payloads.push(self.inner.split_to(idx - offset));
-            let _ = self.inner.split_to(DELIMITER_LEN);
-            offset = idx + DELIMITER_LEN;
-        }
+            if frame_len > MAX_FRAME_LEN {
+                bail!(
+                    "frame is too large ({} bytes, max {} bytes)",
+                    frame_len,
+                    MAX_FRAME_LEN
+                )
+            }
 
-        self.skip_offset = self.inner.len();
+            if self.inner.len() < FRAME_HEADER_LEN + frame_len {
+                break;
+            }
+
+            self.inner.advance(FRAME_HEADER_LEN);
+            payloads.push(self.inner.split_to(frame_len));
+        }
 
         Ok(payloads)
     }
@@ -70,46 +105,46 @@ mod tests {
     use super::*;
 
     #[test]
-    fn encrypted_payload_parse_ends_with_delimiter() {
+    fn encrypted_payload_parse_single_frame() {
         let mut encrypted_payload = EncryptedPayload::new();
-        let delimiter = EncryptedPayload::new_delimiter();
         let all_zeros = [0; 100];
 
-        let mut raw_payload = vec![];
-        raw_payload.extend_from_slice(&all_zeros);
-        raw_payload.extend_from_slice(&delimiter);
+        let raw_payload = EncryptedPayload::encode(&all_zeros).unwrap();
 
-        let parsed_payloads = encrypted_payload.read(&raw_payload, &delimiter).unwrap();
+        let parsed_payloads = encrypted_payload.read(&raw_payload).unwrap();
         assert_eq!(parsed_payloads.len(), 1);
         assert_eq!(parsed_payloads[0], &all_zeros[..]);
     }
 
     #[test]
-    fn encrypted_payload_parse_partial_delimiter() {
+    fn encrypted_payload_parse_partial_header() {
         let mut encrypted_payload = EncryptedPayload::new();
-        let delimiter = EncryptedPayload::new_delimiter();
         let all_zeros = [0; 100];
 
-        let mut raw_payload = vec![];
-        raw_payload.extend_from_slice(&all_zeros);
-        raw_payload.extend_from_slice(&delimiter[0..DELIMITER_LEN / 2]);
+        let raw_payload = EncryptedPayload::encode(&all_zeros).unwrap();
 
-        let parsed_payloads = encrypted_payload.read(&raw_payload, &delimiter).unwrap();
+        let parsed_payloads = encrypted_payload
+            .read(&raw_payload[..FRAME_HEADER_LEN / 2])
+            .unwrap();
         assert_eq!(parsed_payloads.len(), 0);
+
+        let parsed_payloads = encrypted_payload
+            .read(&raw_payload[FRAME_HEADER_LEN / 2..])
+            .unwrap();
+        assert_eq!(parsed_payloads.len(), 1);
+        assert_eq!(parsed_payloads[0], &all_zeros[..]);
     }
 
     #[test]
     fn encrypted_payload_parse_partial_payload() {
         let mut encrypted_payload = EncryptedPayload::new();
-        let delimiter = EncryptedPayload::new_delimiter();
         let all_zeros = [0; 100];
 
         let mut raw_payload = vec![];
-        raw_payload.extend_from_slice(&all_zeros);
-        raw_payload.extend_from_slice(&delimiter);
-        raw_payload.extend_from_slice(&all_zeros[0..10]);
+        raw_payload.extend_from_slice(&EncryptedPayload::encode(&all_zeros).unwrap());
+        raw_payload.extend_from_slice(&EncryptedPayload::encode(&all_zeros).unwrap()[..10]);
 
-        let parsed_payloads = encrypted_payload.read(&raw_payload, &delimiter).unwrap();
+        let parsed_payloads = encrypted_payload.read(&raw_payload).unwrap();
         assert_eq!(parsed_payloads.len(), 1);
         assert_eq!(parsed_payloads[0], &all_zeros[..]);
     }
@@ -117,19 +152,58 @@ mod tests {
     #[test]
     fn encrypted_payload_parse_multiple_payloads() {
         let mut encrypted_payload = EncryptedPayload::new();
-        let delimiter = EncryptedPayload::new_delimiter();
         let all_zeros = [0; 100];
         let all_ones = [1; 100];
 
         let mut raw_payload = vec![];
-        raw_payload.extend_from_slice(&all_zeros);
-        raw_payload.extend_from_slice(&delimiter);
-        raw_payload.extend_from_slice(&all_ones);
-        raw_payload.extend_from_slice(&delimiter);
+        raw_payload.extend_from_slice(&EncryptedPayload::encode(&all_zeros).unwrap());
+        raw_payload.extend_from_slice(&EncryptedPayload::encode(&all_ones).unwrap());
 
-        let parsed_payloads = encrypted_payload.read(&raw_payload, &delimiter).unwrap();
+        let parsed_payloads = encrypted_payload.read(&raw_payload).unwrap();
         assert_eq!(parsed_payloads.len(), 2);
         assert_eq!(parsed_payloads[0], &all_zeros[..]);
         assert_eq!(parsed_payloads[1], &all_ones[..]);
     }
+
+    #[test]
+    fn encrypted_payload_parse_byte_by_byte() {
+        let mut encrypted_payload = EncryptedPayload::new();
+        let all_ones = [1; 100];
+
+        let raw_payload = EncryptedPayload::encode(&all_ones).unwrap();
+        let mut parsed_payloads = vec![];
+
+        for b in raw_payload.iter() {
+            parsed_payloads.extend(encrypted_payload.read(&[*b]).unwrap());
+        }
+
+        assert_eq!(parsed_payloads.len(), 1);
+        assert_eq!(parsed_payloads[0], &all_ones[..]);
+    }
+
+    #[test]
+    fn encrypted_payload_decode_exactly_one_frame() {
+        let all_zeros = [0; 100];
+
+        let mut raw_payload = vec![];
+        raw_payload.extend_from_slice(&EncryptedPayload::encode(&all_zeros).unwrap());
+        assert_eq!(
+            EncryptedPayload::decode(&raw_payload).unwrap(),
+            &all_zeros[..]
+        );
+
+        raw_payload.push(0);
+        assert!(EncryptedPayload::decode(&raw_payload).is_err());
+        assert!(EncryptedPayload::decode(&raw_payload[..10]).is_err());
+    }
+
+    #[test]
+    fn encrypted_payload_reject_too_large_frame() {
+        let mut encrypted_payload = EncryptedPayload::new();
+
+        assert!(EncryptedPayload::encode(&vec![0; MAX_FRAME_LEN + 1]).is_err());
+        assert!(encrypted_payload
+            .read(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes())
+            .is_err());
+    }
 }
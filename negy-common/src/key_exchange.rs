@@ -0,0 +1,169 @@
+use crate::aes::KEY_LEN;
+use anyhow::{bail, Result};
+use openssl::derive::Deriver;
+use openssl::hash::MessageDigest;
+use openssl::md::Md;
+use openssl::pkey::{HasPublic, Id, PKey, Private, Public};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::{Signer, Verifier};
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+const TRANSCRIPT_LABEL: &[u8] = b"negy-x25519-rsa-sha256";
+
+// Ephemeral X25519 key exchange between the gateway and a node.
+// The node signs the transcript with its long-lived identity key, so the gateway knows it's talking
+// to the node listed on the node pool while session keys can't be recovered from the identity key later.
+pub struct KeyExchange {
+    private_key: PKey<Private>,
+}
+
+impl KeyExchange {
+    pub fn new() -> Result<Self> {
+        Ok(KeyExchange {
+            private_key: PKey::generate_x25519()?,
+        })
+    }
+
+    pub fn public_key(&self) -> Result<[u8; PUBLIC_KEY_LEN]> {
+        let raw = self.private_key.raw_public_key()?;
+        let mut public_key = [0; PUBLIC_KEY_LEN];
+
+        public_key.copy_from_slice(&raw);
+
+        Ok(public_key)
+    }
+
+    pub fn derive(
+        &self,
+        peer_public_key: &[u8; PUBLIC_KEY_LEN],
+        transcript: &[u8],
+    ) -> Result<[u8; KEY_LEN]> {
+        let peer_public_key: PKey<Public> =
+            PKey::public_key_from_raw_bytes(peer_public_key, Id::X25519)?;
+
+        let mut deriver = Deriver::new(&self.private_key)?;
+        deriver.set_peer(&peer_public_key)?;
+
+        let shared_secret = deriver.derive_to_vec()?;
+
+        if shared_secret.iter().all(|b| *b == 0) {
+            bail!("invalid peer public key (shared secret is all zeros)")
+        }
+
+        let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+        ctx.derive_init()?;
+        ctx.set_hkdf_md(Md::sha256())?;
+        ctx.set_hkdf_key(&shared_secret)?;
+        ctx.add_hkdf_info(transcript)?;
+
+        let mut key = [0; KEY_LEN];
+        ctx.derive(Some(&mut key))?;
+
+        Ok(key)
+    }
+}
+
+pub fn transcript(
+    gateway_public_key: &[u8; PUBLIC_KEY_LEN],
+    node_public_key: &[u8; PUBLIC_KEY_LEN],
+    identity_der: &[u8],
+) -> Vec<u8> {
+    let mut transcript = Vec::new();
+
+    transcript.extend_from_slice(TRANSCRIPT_LABEL);
+    transcript.extend_from_slice(gateway_public_key);
+    transcript.extend_from_slice(node_public_key);
+    transcript.extend_from_slice(identity_der);
+
+    transcript
+}
+
+pub fn sign(rsa: &Rsa<Private>, transcript: &[u8]) -> Result<Vec<u8>> {
+    let pkey = PKey::from_rsa(rsa.clone())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+
+    signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+
+    Ok(signer.sign_oneshot_to_vec(transcript)?)
+}
+
+pub fn verify(rsa: &Rsa<Public>, transcript: &[u8], signature: &[u8]) -> Result<()> {
+    let pkey = PKey::from_rsa(rsa.clone())?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)?;
+
+    verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+
+    if !verifier.verify_oneshot(signature, transcript)? {
+        bail!("invalid handshake signature")
+    }
+
+    Ok(())
+}
+
+// Hex SHA-256 of the DER identity key, which names a node independently of its address.
+pub fn fingerprint<T: HasPublic>(rsa: &Rsa<T>) -> Result<String> {
+    Ok(openssl::sha::sha256(&rsa.public_key_to_der()?)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_rsa(rsa: &Rsa<Private>) -> Rsa<Public> {
+        Rsa::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn key_exchange_derive_same_key() {
+        let gateway = KeyExchange::new().unwrap();
+        let node = KeyExchange::new().unwrap();
+
+        let gateway_public_key = gateway.public_key().unwrap();
+        let node_public_key = node.public_key().unwrap();
+        let transcript = transcript(&gateway_public_key, &node_public_key, b"identity");
+
+        assert_eq!(
+            gateway.derive(&node_public_key, &transcript).unwrap(),
+            node.derive(&gateway_public_key, &transcript).unwrap()
+        );
+    }
+
+    #[test]
+    fn key_exchange_reject_zero_public_key() {
+        let gateway = KeyExchange::new().unwrap();
+
+        assert!(gateway.derive(&[0; PUBLIC_KEY_LEN], b"transcript").is_err());
+    }
+
+    #[test]
+    fn key_exchange_verify_signature() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let signature = sign(&rsa, b"transcript").unwrap();
+
+        assert!(verify(&public_rsa(&rsa), b"transcript", &signature).is_ok());
+        assert!(verify(&public_rsa(&rsa), b"tampered", &signature).is_err());
+    }
+
+    #[test]
+    fn key_exchange_reject_signature_by_other_identity() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let other = Rsa::generate(2048).unwrap();
+        let signature = sign(&other, b"transcript").unwrap();
+
+        assert!(verify(&public_rsa(&rsa), b"transcript", &signature).is_err());
+    }
+
+    #[test]
+    fn key_exchange_fingerprint_of_public_key() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let fingerprint = fingerprint(&rsa).unwrap();
+
+        assert_eq!(fingerprint.len(), 64);
+        assert_eq!(fingerprint, super::fingerprint(&public_rsa(&rsa)).unwrap());
+    }
+}
@@ -1,3 +1,10 @@
 pub mod aes;
+pub mod cell;
+pub mod cidr;
+pub mod destination;
 pub mod encrypted_payload;
+pub mod key_exchange;
+pub mod oaep;
+pub mod policy;
 pub mod protocol;
+pub mod stream;
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, bail, Result};
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::{Padding, Rsa};
+
+// RSA-OAEP with SHA-256 for both the label hash and MGF1.
+pub fn encrypt(rsa: &Rsa<Public>, data: &[u8]) -> Result<Vec<u8>> {
+    let pkey = PKey::from_rsa(rsa.clone())?;
+    let mut encrypter = Encrypter::new(&pkey)?;
+
+    encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    encrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+    encrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+
+    if data.len() > max_len(rsa.size() as usize) {
+        bail!(
+            "data is too large for RSA-OAEP ({} bytes, max {} bytes)",
+            data.len(),
+            max_len(rsa.size() as usize)
+        )
+    }
+
+    let mut encrypted = vec![0; encrypter.encrypt_len(data)?];
+    let n = encrypter.encrypt(data, &mut encrypted)?;
+    encrypted.truncate(n);
+
+    Ok(encrypted)
+}
+
+pub fn decrypt(rsa: &Rsa<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let pkey = PKey::from_rsa(rsa.clone())?;
+    let mut decrypter = Decrypter::new(&pkey)?;
+
+    decrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+    decrypter.set_rsa_oaep_md(MessageDigest::sha256())?;
+    decrypter.set_rsa_mgf1_md(MessageDigest::sha256())?;
+
+    let mut decrypted = vec![0; decrypter.decrypt_len(data)?];
+    let n = decrypter
+        .decrypt(data, &mut decrypted)
+        .map_err(|_| anyhow!("failed to decrypt RSA-OAEP payload"))?;
+    decrypted.truncate(n);
+
+    Ok(decrypted)
+}
+
+pub fn max_len(rsa_key_len: usize) -> usize {
+    rsa_key_len - 2 * MessageDigest::sha256().size() - 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public_rsa(rsa: &Rsa<Private>) -> Rsa<Public> {
+        Rsa::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn oaep_encrypt_decrypt() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let encrypted = encrypt(&public_rsa(&rsa), b"127.0.0.1:3000").unwrap();
+
+        assert_eq!(encrypted.len(), rsa.size() as usize);
+        assert_eq!(decrypt(&rsa, &encrypted).unwrap(), b"127.0.0.1:3000");
+    }
+
+    #[test]
+    fn oaep_reject_pkcs1_payload() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let mut encrypted = vec![0; rsa.size() as usize];
+
+        public_rsa(&rsa)
+            .public_encrypt(b"127.0.0.1:3000", &mut encrypted, Padding::PKCS1)
+            .unwrap();
+
+        assert!(decrypt(&rsa, &encrypted).is_err());
+    }
+
+    #[test]
+    fn oaep_reject_too_large_data() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let data = vec![0; max_len(rsa.size() as usize) + 1];
+
+        assert!(encrypt(&public_rsa(&rsa), &data).is_err());
+    }
+}
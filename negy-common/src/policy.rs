@@ -0,0 +1,290 @@
+use crate::cidr::Cidr;
+use anyhow::{bail, Result};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Loopback, RFC 1918, CGNAT, link-local and unique local ranges.
+const PRIVATE_RANGES: [&str; 10] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "::/127",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Accept,
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pattern {
+    Any,
+    // private and local addresses, including `localhost`
+    Private,
+    Cidr(Cidr),
+    // `*` and `?` wildcards, like `*.example.com`
+    Host(String),
+}
+
+// `<accept|reject> <pattern>[:<ports>]`, e.g. `reject private`, `reject *:25`,
+// `accept *.example.com:443`, `reject [fc00::]/7:1-1024`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pattern: Pattern,
+    ports: (u16, u16),
+    source: String,
+}
+
+impl Rule {
+    // `ip` is the address the host resolved to, if known.
+    // Without it, address rules only match hosts which are IP literals.
+    // Hostnames are compared case-insensitively and without the trailing dot of a FQDN.
+    fn matches(&self, host: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        if port < self.ports.0 || port > self.ports.1 {
+            return false;
+        }
+
+        let host = host.strip_suffix('.').unwrap_or(host).to_lowercase();
+        let host = host.as_str();
+        let ip = ip.or_else(|| host.parse().ok());
+
+        match &self.pattern {
+            Pattern::Any => true,
+            Pattern::Private => match ip {
+                Some(ip) => is_private(&ip),
+                None => host == "localhost" || host.ends_with(".localhost"),
+            },
+            Pattern::Cidr(cidr) => ip.map(|ip| cidr.contains(&ip)).unwrap_or(false),
+            Pattern::Host(glob) => glob_match(glob.as_bytes(), host.as_bytes()),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (action, target) = match s.trim().split_once(char::is_whitespace) {
+            Some((action, target)) => (action, target.trim()),
+            None => bail!("invalid policy rule {:?}", s),
+        };
+
+        let action = match action.to_lowercase().as_str() {
+            "accept" => Action::Accept,
+            "reject" => Action::Reject,
+            _ => bail!("invalid policy action {:?} in {:?}", action, s),
+        };
+
+        let (pattern, ports) = split_ports(target)?;
+
+        let pattern = match pattern.to_lowercase().as_str() {
+            "*" => Pattern::Any,
+            "private" => Pattern::Private,
+            pattern if pattern.starts_with('[') => {
+                Pattern::Cidr(pattern.replacen('[', "", 1).replacen(']', "", 1).parse()?)
+            }
+            pattern if pattern.parse::<Cidr>().is_ok() => Pattern::Cidr(pattern.parse()?),
+            pattern => {
+                if pattern.is_empty() || pattern.contains(&[':', '/', '[', ']'][..]) {
+                    bail!("invalid policy pattern {:?} in {:?}", pattern, s)
+                }
+
+                Pattern::Host(pattern.to_owned())
+            }
+        };
+
+        Ok(Rule {
+            action,
+            pattern,
+            ports,
+            source: s.trim().to_owned(),
+        })
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Rules are evaluated in order and the first match wins. Nothing matched is accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+        Ok(Policy {
+            rules: rules
+                .iter()
+                .map(|r| r.as_ref().parse())
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    // Returns the rejecting rule as the reason.
+    pub fn check(&self, host: &str, ip: Option<IpAddr>, port: u16) -> Result<(), String> {
+        match self.rules.iter().find(|r| r.matches(host, ip, port)) {
+            Some(rule) if rule.action == Action::Reject => Err(format!(
+                "{} is rejected by policy rule `{}`",
+                display_host_port(host, port),
+                rule
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether anything on the port could be accepted, for destinations not known in advance.
+    pub fn accepts_port(&self, port: u16) -> bool {
+        for rule in &self.rules {
+            if port < rule.ports.0 || port > rule.ports.1 {
+                continue;
+            }
+
+            match (rule.action, &rule.pattern) {
+                (Action::Accept, _) => return true,
+                (Action::Reject, Pattern::Any) => return false,
+                (Action::Reject, _) => {}
+            }
+        }
+
+        true
+    }
+}
+
+pub fn is_private(ip: &IpAddr) -> bool {
+    PRIVATE_RANGES
+        .iter()
+        .any(|range| range.parse::<Cidr>().unwrap().contains(ip))
+}
+
+fn split_ports(target: &str) -> Result<(&str, (u16, u16))> {
+    // brackets keep the colons of IPv6 out of the way
+    let port_sep = match target.rfind(']') {
+        Some(i) => target[i..].find(':').map(|j| i + j),
+        None => target.rfind(':'),
+    };
+
+    let (pattern, ports) = match port_sep {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, "*"),
+    };
+
+    let ports = match ports.split_once('-') {
+        _ if ports == "*" => (1, u16::MAX),
+        Some((from, to)) => (from.parse()?, to.parse()?),
+        None => (ports.parse()?, ports.parse()?),
+    };
+
+    if ports.0 == 0 || ports.0 > ports.1 {
+        bail!("invalid port range in policy rule {:?}", target)
+    }
+
+    Ok((pattern, ports))
+}
+
+fn display_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn glob_match(glob: &[u8], s: &[u8]) -> bool {
+    match glob.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: &[&str]) -> Policy {
+        Policy::parse(rules).unwrap()
+    }
+
+    #[test]
+    fn policy_first_match_wins() {
+        let p = policy(&["accept 10.1.0.0/16", "reject private", "reject *:25"]);
+
+        assert!(p.check("10.1.2.3", None, 80).is_ok());
+        assert!(p.check("10.2.0.1", None, 80).is_err());
+        assert!(p.check("localhost", None, 80).is_err());
+        assert!(p.check("example.com", None, 25).is_err());
+        assert!(p.check("example.com", None, 443).is_ok());
+    }
+
+    #[test]
+    fn policy_match_resolved_address() {
+        let p = policy(&["reject private"]);
+
+        assert!(p.check("example.com", None, 80).is_ok());
+        assert!(p
+            .check("example.com", Some("192.168.0.1".parse().unwrap()), 80)
+            .is_err());
+        assert!(p.check("::ffff:127.0.0.1", None, 80).is_err());
+    }
+
+    #[test]
+    fn policy_match_hostname_glob() {
+        let p = policy(&["accept *.example.com:443", "reject *"]);
+
+        assert!(p.check("www.example.com", None, 443).is_ok());
+        assert!(p.check("a.b.example.com", None, 443).is_ok());
+        assert!(p.check("example.com", None, 443).is_err());
+        assert!(p.check("www.example.com", None, 80).is_err());
+    }
+
+    #[test]
+    fn policy_match_hostname_normalized() {
+        let p = policy(&["reject *.example.com", "reject private"]);
+
+        assert!(p.check("WWW.Example.COM", None, 80).is_err());
+        assert!(p.check("www.example.com.", None, 80).is_err());
+        assert!(p.check("LocalHost.", None, 80).is_err());
+        assert!(p.check("www.example.org.", None, 80).is_ok());
+    }
+
+    #[test]
+    fn policy_parse_ports_and_ipv6() {
+        let p = policy(&["reject [fc00::]/7:1-1024", "reject [::1]"]);
+
+        assert!(p.check("fd00::1", None, 80).is_err());
+        assert!(p.check("fd00::1", None, 8080).is_ok());
+        assert!(p.check("::1", None, 8080).is_err());
+
+        assert!("allow *".parse::<Rule>().is_err());
+        assert!("reject *:0".parse::<Rule>().is_err());
+        assert!("reject *:90-80".parse::<Rule>().is_err());
+        assert!("reject fc00::1".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn policy_accepts_port() {
+        let p = policy(&["reject private", "accept *:443", "reject *"]);
+
+        assert!(p.accepts_port(443));
+        assert!(!p.accepts_port(80));
+        assert!(Policy::default().accepts_port(80));
+    }
+}
@@ -1,6 +1,22 @@
 use anyhow::{bail, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub const PROTOCOL_SYMBOL_LEN: usize = 1;
+// v1: PKCS#1 v1.5 onion header
+// v2: RSA-OAEP (SHA-256) onion header
+// v3: handshake header with magic and explicit length fields
+// v4: typed handshake replies
+// v5: exit connects to the destination after the circuit is built
+// v6: streams multiplexed over a circuit with cells
+// v7: destinations resolved by the exit and reported back
+// v8: successor replies sealed by each hop
+pub const PROTOCOL_VERSION: u8 = 8;
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"NEGY";
+pub const HANDSHAKE_HEADER_LEN: usize = 4 + 1 + 2 + 4;
+pub const MAX_HANDSHAKE_LEN: usize = 1 << 16;
+// Circuits are at most this long, which bounds the successor replies the gateway opens.
+pub const MAX_HOPS: usize = 16;
 pub const TUNNEL: u8 = 1;
 pub const NODE_CONTEXT: u8 = 2;
 
@@ -19,7 +35,7 @@ impl Protocol {
     }
 
     pub fn parse(bytes: &[u8]) -> Result<Protocol> {
-        if bytes.len() == 0 {
+        if bytes.is_empty() {
             bail!("Protocol error. Symbol byte is 0 byte.")
         }
 
@@ -32,3 +48,335 @@ impl Protocol {
         }
     }
 }
+
+// Fixed size header which follows the symbol byte of a tunnel handshake.
+// `hop_header_len` bytes are for the receiving node, the rest of `total_len` is forwarded to its successor.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HandshakeHeader {
+    pub hop_header_len: usize,
+    pub total_len: usize,
+}
+
+impl HandshakeHeader {
+    pub fn new(hop_header_len: usize, total_len: usize) -> Result<Self> {
+        let header = HandshakeHeader {
+            hop_header_len,
+            total_len,
+        };
+
+        header.validate()?;
+
+        Ok(header)
+    }
+
+    pub fn encode(&self, bytes: &mut BytesMut) {
+        bytes.extend_from_slice(&HANDSHAKE_MAGIC);
+        bytes.put_u8(PROTOCOL_VERSION);
+        bytes.put_u16(self.hop_header_len as u16);
+        bytes.put_u32(self.total_len as u32);
+    }
+
+    pub fn parse(mut bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HANDSHAKE_HEADER_LEN {
+            bail!("Protocol error. Handshake header is too short.")
+        }
+
+        if bytes[..HANDSHAKE_MAGIC.len()] != HANDSHAKE_MAGIC {
+            bail!("Protocol error. Invalid handshake magic. The peer may speak an older protocol.")
+        }
+
+        bytes.advance(HANDSHAKE_MAGIC.len());
+
+        let version = bytes.get_u8();
+
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "Protocol error. Unsupported protocol version {} (expected {}). The peer must be upgraded.",
+                version,
+                PROTOCOL_VERSION
+            )
+        }
+
+        let hop_header_len = bytes.get_u16() as usize;
+        let total_len = bytes.get_u32() as usize;
+
+        HandshakeHeader::new(hop_header_len, total_len)
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(Self, BytesMut)> {
+        let mut header_bytes = [0; HANDSHAKE_HEADER_LEN];
+        reader.read_exact(&mut header_bytes).await?;
+
+        let header = HandshakeHeader::parse(&header_bytes)?;
+
+        let mut payload = BytesMut::zeroed(header.total_len);
+        reader.read_exact(&mut payload).await?;
+
+        Ok((header, payload))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.hop_header_len > u16::MAX as usize {
+            bail!(
+                "Protocol error. Hop header is too large ({} bytes).",
+                self.hop_header_len
+            )
+        }
+
+        if self.total_len > MAX_HANDSHAKE_LEN {
+            bail!(
+                "Protocol error. Handshake is too large ({} bytes, max {} bytes).",
+                self.total_len,
+                MAX_HANDSHAKE_LEN
+            )
+        }
+
+        if self.hop_header_len > self.total_len {
+            bail!(
+                "Protocol error. Hop header length {} exceeds total length {}.",
+                self.hop_header_len,
+                self.total_len
+            )
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReplyStatus {
+    Ok,
+    ProtocolError,
+    DecryptFailed,
+    UpstreamConnectFailed,
+    UpstreamProtocolError,
+    PolicyDenied,
+    Timeout,
+}
+
+impl ReplyStatus {
+    pub fn code(&self) -> u8 {
+        match &self {
+            ReplyStatus::Ok => 0,
+            ReplyStatus::ProtocolError => 1,
+            ReplyStatus::DecryptFailed => 2,
+            ReplyStatus::UpstreamConnectFailed => 3,
+            ReplyStatus::UpstreamProtocolError => 4,
+            ReplyStatus::PolicyDenied => 5,
+            ReplyStatus::Timeout => 6,
+        }
+    }
+
+    pub fn parse(code: u8) -> Result<ReplyStatus> {
+        match code {
+            0 => Ok(ReplyStatus::Ok),
+            1 => Ok(ReplyStatus::ProtocolError),
+            2 => Ok(ReplyStatus::DecryptFailed),
+            3 => Ok(ReplyStatus::UpstreamConnectFailed),
+            4 => Ok(ReplyStatus::UpstreamProtocolError),
+            5 => Ok(ReplyStatus::PolicyDenied),
+            6 => Ok(ReplyStatus::Timeout),
+            _ => bail!("Protocol error. Unknown reply status {}", code),
+        }
+    }
+
+    // Whether the status is about the connection from the reporting node to its upstream
+    // rather than about the reporting node itself.
+    pub fn is_upstream_failure(&self) -> bool {
+        matches!(
+            self,
+            ReplyStatus::UpstreamConnectFailed
+                | ReplyStatus::UpstreamProtocolError
+                | ReplyStatus::Timeout
+        )
+    }
+}
+
+// Handshake reply sent by each node to its predecessor.
+// A relay embeds the reply of its successor sealed with its own session key,
+// so only the gateway opens it, one hop at a time, and can tell which hop failed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub status: ReplyStatus,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub message: String,
+    pub successor: Vec<u8>,
+}
+
+impl Reply {
+    pub fn ok(public_key: &[u8], signature: &[u8]) -> Self {
+        Reply {
+            status: ReplyStatus::Ok,
+            public_key: public_key.to_vec(),
+            signature: signature.to_vec(),
+            message: String::new(),
+            successor: Vec::new(),
+        }
+    }
+
+    pub fn failure(status: ReplyStatus, message: &str) -> Self {
+        Reply {
+            status,
+            public_key: Vec::new(),
+            signature: Vec::new(),
+            message: message.to_owned(),
+            successor: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Result<BytesMut> {
+        if self.public_key.len() > u16::MAX as usize
+            || self.signature.len() > u16::MAX as usize
+            || self.message.len() > u16::MAX as usize
+            || self.successor.len() > u32::MAX as usize
+        {
+            bail!("Protocol error. Reply field is too large.")
+        }
+
+        let mut bytes = BytesMut::new();
+
+        bytes.put_u8(self.status.code());
+        bytes.put_u16(self.public_key.len() as u16);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.put_u16(self.signature.len() as u16);
+        bytes.extend_from_slice(&self.signature);
+        bytes.put_u16(self.message.len() as u16);
+        bytes.extend_from_slice(self.message.as_bytes());
+        bytes.put_u32(self.successor.len() as u32);
+        bytes.extend_from_slice(&self.successor);
+
+        Ok(bytes)
+    }
+
+    pub fn parse(mut bytes: &[u8]) -> Result<Self> {
+        let status = ReplyStatus::parse(Reply::take(&mut bytes, 1)?[0])?;
+
+        let public_key_len = Reply::take(&mut bytes, 2)?.get_u16() as usize;
+        let public_key = Reply::take(&mut bytes, public_key_len)?.to_vec();
+
+        let signature_len = Reply::take(&mut bytes, 2)?.get_u16() as usize;
+        let signature = Reply::take(&mut bytes, signature_len)?.to_vec();
+
+        let message_len = Reply::take(&mut bytes, 2)?.get_u16() as usize;
+        let message = String::from_utf8(Reply::take(&mut bytes, message_len)?.to_vec())?;
+
+        let successor_len = Reply::take(&mut bytes, 4)?.get_u32() as usize;
+        let successor = Reply::take(&mut bytes, successor_len)?.to_vec();
+
+        if !bytes.is_empty() {
+            bail!("Protocol error. Unexpected trailing bytes in reply.")
+        }
+
+        Ok(Reply {
+            status,
+            public_key,
+            signature,
+            message,
+            successor,
+        })
+    }
+
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+        if bytes.len() < len {
+            bail!("Protocol error. Reply is too short.")
+        }
+
+        let (head, tail) = bytes.split_at(len);
+        *bytes = tail;
+
+        Ok(head)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_header_encode_parse() {
+        let header = HandshakeHeader::new(288, 1024).unwrap();
+        let mut bytes = BytesMut::new();
+        header.encode(&mut bytes);
+
+        assert_eq!(bytes.len(), HANDSHAKE_HEADER_LEN);
+        assert_eq!(HandshakeHeader::parse(&bytes).unwrap(), header);
+    }
+
+    #[test]
+    fn handshake_header_reject_invalid_magic() {
+        let mut bytes = BytesMut::new();
+        HandshakeHeader::new(288, 1024).unwrap().encode(&mut bytes);
+        bytes[0] = b'X';
+
+        assert!(HandshakeHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn handshake_header_reject_other_version() {
+        let mut bytes = BytesMut::new();
+        HandshakeHeader::new(288, 1024).unwrap().encode(&mut bytes);
+        bytes[HANDSHAKE_MAGIC.len()] = PROTOCOL_VERSION - 1;
+
+        assert!(HandshakeHeader::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn handshake_header_reject_inconsistent_lengths() {
+        assert!(HandshakeHeader::new(1024, 288).is_err());
+        assert!(HandshakeHeader::new(288, MAX_HANDSHAKE_LEN + 1).is_err());
+    }
+
+    #[test]
+    fn handshake_header_reject_short_header() {
+        let mut bytes = BytesMut::new();
+        HandshakeHeader::new(288, 1024).unwrap().encode(&mut bytes);
+
+        assert!(HandshakeHeader::parse(&bytes[..HANDSHAKE_HEADER_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn reply_encode_parse_with_successor() {
+        let mut reply = Reply::ok(&[1; 32], &[2; 256]);
+        reply.successor = Reply::failure(ReplyStatus::UpstreamConnectFailed, "connection refused")
+            .encode()
+            .unwrap()
+            .to_vec();
+
+        let bytes = reply.encode().unwrap();
+        let parsed = Reply::parse(&bytes).unwrap();
+        assert_eq!(parsed, reply);
+        assert_eq!(
+            Reply::parse(&parsed.successor).unwrap(),
+            Reply::failure(ReplyStatus::UpstreamConnectFailed, "connection refused")
+        );
+    }
+
+    #[test]
+    fn reply_successor_is_opaque() {
+        let mut reply = Reply::failure(ReplyStatus::Timeout, "timeout");
+        reply.successor = vec![0xff; 64];
+
+        let bytes = reply.encode().unwrap();
+        assert_eq!(Reply::parse(&bytes).unwrap().successor, vec![0xff; 64]);
+    }
+
+    #[test]
+    fn reply_reject_malformed() {
+        let bytes = Reply::ok(&[1; 32], &[2; 256]).encode().unwrap();
+
+        assert!(Reply::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Reply::parse(&[0xff]).is_err());
+
+        let mut trailing = bytes.to_vec();
+        trailing.push(0);
+        assert!(Reply::parse(&trailing).is_err());
+    }
+
+    #[test]
+    fn reply_status_code_roundtrip() {
+        for code in 0..=6 {
+            assert_eq!(ReplyStatus::parse(code).unwrap().code(), code);
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use crate::cell::{Cell, MAX_DATA_LEN};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{mpsc, Semaphore};
+
+// Bytes a stream may have in flight before the peer grants more with a window cell.
+// Cells are queued without bounds, so this is what keeps a slow reader from exhausting memory.
+pub const STREAM_WINDOW: usize = 256 * 1024;
+
+pub struct SendWindow {
+    permits: Semaphore,
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SendWindow {
+    pub fn new() -> Self {
+        SendWindow {
+            permits: Semaphore::new(STREAM_WINDOW),
+        }
+    }
+
+    // Reserves room for one full data cell. The unused part must be given back with `refund`.
+    pub async fn reserve(&self) -> Result<()> {
+        self.permits
+            .acquire_many(MAX_DATA_LEN as u32)
+            .await?
+            .forget();
+
+        Ok(())
+    }
+
+    pub fn refund(&self, unused: usize) {
+        self.permits.add_permits(unused);
+    }
+
+    pub fn grant(&self, increment: u32) -> Result<()> {
+        if self.permits.available_permits() + increment as usize > STREAM_WINDOW {
+            bail!("Protocol error. Stream window overflow.")
+        }
+
+        self.permits.add_permits(increment as usize);
+
+        Ok(())
+    }
+}
+
+// Counts consumed bytes and tells when to grant them back to the sender.
+#[derive(Default)]
+pub struct RecvWindow {
+    consumed: usize,
+}
+
+impl RecvWindow {
+    pub fn new() -> Self {
+        RecvWindow { consumed: 0 }
+    }
+
+    pub fn consume(&mut self, len: usize) -> Option<u32> {
+        self.consumed += len;
+
+        if self.consumed < STREAM_WINDOW / 4 {
+            return None;
+        }
+
+        let increment = self.consumed as u32;
+        self.consumed = 0;
+
+        Some(increment)
+    }
+}
+
+struct Entry {
+    tx: UnboundedSender<Cell>,
+    window: Arc<SendWindow>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<u32, Entry>,
+    closed: bool,
+}
+
+// Streams of one circuit, keyed by stream id.
+#[derive(Clone, Default)]
+pub struct Streams {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        Streams {
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    // `tx` carries the cells of the new stream to the circuit.
+    pub fn register(&self, stream_id: u32, tx: UnboundedSender<Cell>) -> Result<Stream> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.closed {
+            bail!("circuit is closed")
+        }
+
+        if inner.entries.contains_key(&stream_id) {
+            bail!("Protocol error. Stream #{} is already open.", stream_id)
+        }
+
+        let (stream_tx, stream_rx) = mpsc::unbounded_channel();
+        let window = Arc::new(SendWindow::new());
+
+        inner.entries.insert(
+            stream_id,
+            Entry {
+                tx: stream_tx,
+                window: window.clone(),
+            },
+        );
+
+        Ok(Stream {
+            id: stream_id,
+            rx: stream_rx,
+            tx,
+            window,
+            streams: Arc::downgrade(&self.inner),
+        })
+    }
+
+    // Cells for streams which are already gone are dropped.
+    pub fn dispatch(&self, cell: Cell) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let stream_id = cell.stream_id();
+
+        match cell {
+            Cell::Open { .. } => bail!("Protocol error. Unexpected open cell."),
+            Cell::Window { increment, .. } => {
+                if let Some(entry) = inner.entries.get(&stream_id) {
+                    entry.window.grant(increment)?;
+                }
+            }
+            Cell::Close { .. } => {
+                if let Some(entry) = inner.entries.remove(&stream_id) {
+                    let _ = entry.tx.send(cell);
+                }
+            }
+            cell => {
+                if let Some(entry) = inner.entries.get(&stream_id) {
+                    let _ = entry.tx.send(cell);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().closed
+    }
+
+    // Ends every stream and refuses new ones.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.closed = true;
+        inner.entries.clear();
+    }
+}
+
+pub struct Stream {
+    pub id: u32,
+    rx: UnboundedReceiver<Cell>,
+    tx: UnboundedSender<Cell>,
+    window: Arc<SendWindow>,
+    streams: Weak<Mutex<Inner>>,
+}
+
+impl Stream {
+    pub fn send(&self, cell: Cell) -> Result<()> {
+        self.tx.send(cell).map_err(|_| anyhow!("circuit is closed"))
+    }
+
+    // Sends data which didn't come from the piped socket, like a request rewritten by the gateway.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        for chunk in data.chunks(MAX_DATA_LEN) {
+            self.window.reserve().await?;
+            self.window.refund(MAX_DATA_LEN - chunk.len());
+
+            self.send(Cell::Data {
+                stream_id: self.id,
+                data: chunk.to_vec(),
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Returns None once the circuit is gone.
+    pub async fn recv(&mut self) -> Option<Cell> {
+        self.rx.recv().await
+    }
+
+    // Copies between the socket and the stream until either side closes.
+    pub async fn pipe<S: AsyncRead + AsyncWrite + Unpin>(mut self, socket: S) -> Result<()> {
+        let (mut s_rx, mut s_tx) = tokio::io::split(socket);
+        let stream_id = self.id;
+        let tx = &self.tx;
+        let window = &self.window;
+        let rx = &mut self.rx;
+
+        let upload = async {
+            let mut s_bytes = vec![0; MAX_DATA_LEN];
+
+            loop {
+                window.reserve().await?;
+                let n = s_rx.read(&mut s_bytes).await?;
+                window.refund(MAX_DATA_LEN - n);
+
+                if n == 0 {
+                    return Ok(false);
+                }
+
+                tx.send(Cell::Data {
+                    stream_id,
+                    data: s_bytes[..n].to_vec(),
+                })?;
+            }
+        };
+
+        let download = async {
+            let mut recv_window = RecvWindow::new();
+
+            while let Some(cell) = rx.recv().await {
+                match cell {
+                    Cell::Data { data, .. } => {
+                        s_tx.write_all(&data).await?;
+
+                        if let Some(increment) = recv_window.consume(data.len()) {
+                            tx.send(Cell::Window {
+                                stream_id,
+                                increment,
+                            })?;
+                        }
+                    }
+                    Cell::Close { .. } => return Ok(true),
+                    cell => bail!("Protocol error. Unexpected cell {:?}.", cell),
+                }
+            }
+
+            Ok(false)
+        };
+
+        // true when the peer closed the stream
+        let result: Result<bool> = tokio::select! {
+            r = upload => r,
+            r = download => r,
+        };
+
+        if !matches!(result, Ok(true)) {
+            let _ = self.tx.send(Cell::Close { stream_id });
+        }
+
+        result.map(|_| ())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(streams) = self.streams.upgrade() {
+            streams.lock().unwrap().entries.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recv_window_grant_after_quarter() {
+        let mut recv_window = RecvWindow::new();
+
+        assert_eq!(recv_window.consume(STREAM_WINDOW / 8), None);
+        assert_eq!(
+            recv_window.consume(STREAM_WINDOW / 8),
+            Some((STREAM_WINDOW / 4) as u32)
+        );
+        assert_eq!(recv_window.consume(1), None);
+    }
+
+    #[test]
+    fn send_window_reject_overflow() {
+        let send_window = SendWindow::new();
+
+        assert!(send_window.grant(1).is_err());
+    }
+
+    #[tokio::test]
+    async fn send_window_reserve_refund_grant() {
+        let send_window = SendWindow::new();
+
+        send_window.reserve().await.unwrap();
+        send_window.refund(MAX_DATA_LEN - 100);
+        assert_eq!(send_window.permits.available_permits(), STREAM_WINDOW - 100);
+
+        send_window.grant(100).unwrap();
+        assert_eq!(send_window.permits.available_permits(), STREAM_WINDOW);
+    }
+
+    #[test]
+    fn streams_dispatch_to_registered_stream() {
+        let streams = Streams::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut stream = streams.register(1, tx.clone()).unwrap();
+
+        assert!(streams.register(1, tx.clone()).is_err());
+
+        streams
+            .dispatch(Cell::Data {
+                stream_id: 1,
+                data: vec![1],
+            })
+            .unwrap();
+        streams
+            .dispatch(Cell::Data {
+                stream_id: 2,
+                data: vec![2],
+            })
+            .unwrap();
+
+        assert_eq!(
+            stream.rx.try_recv().unwrap(),
+            Cell::Data {
+                stream_id: 1,
+                data: vec![1]
+            }
+        );
+        assert!(stream.rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn streams_remove_dropped_stream() {
+        let streams = Streams::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let stream = streams.register(1, tx).unwrap();
+
+        assert_eq!(streams.len(), 1);
+        drop(stream);
+        assert!(streams.is_empty());
+    }
+
+    #[test]
+    fn streams_refuse_after_close() {
+        let streams = Streams::new();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut stream = streams.register(1, tx.clone()).unwrap();
+
+        streams.close();
+
+        assert!(stream.rx.try_recv().is_err());
+        assert!(streams.register(2, tx).is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_pipe_both_directions() {
+        let streams = Streams::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let stream = streams.register(1, tx).unwrap();
+        let (socket, mut peer) = tokio::io::duplex(1024);
+
+        let pipe = tokio::spawn(stream.pipe(socket));
+
+        peer.write_all(b"upload").await.unwrap();
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            Cell::Data {
+                stream_id: 1,
+                data: b"upload".to_vec()
+            }
+        );
+
+        streams
+            .dispatch(Cell::Data {
+                stream_id: 1,
+                data: b"download".to_vec(),
+            })
+            .unwrap();
+        let mut bytes = [0; 8];
+        peer.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(&bytes, b"download");
+
+        drop(peer);
+        pipe.await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Cell::Close { stream_id: 1 });
+    }
+}
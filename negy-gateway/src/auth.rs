@@ -0,0 +1,371 @@
+use crate::http::proxy_auth_required;
+use anyhow::{bail, Result};
+use negy_common::protocol::MAX_HOPS;
+use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Semaphore;
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const HASH_ITERATIONS: usize = 100_000;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+// PBKDF2 runs on blocking threads, so failed logins can't stall the runtime,
+// and only a few at a time, so they can't take every core either.
+const MAX_CONCURRENT_HASHES: usize = 4;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub username: String,
+    password_hash: String,
+    #[serde(default)]
+    pub hops: Option<usize>,
+    #[serde(default)]
+    pub requests_per_second: Option<u32>,
+    #[serde(default)]
+    pub max_connections: Option<usize>,
+    // bytes in both directions, reset every `period` seconds
+    #[serde(default)]
+    pub bytes_per_period: Option<u64>,
+    #[serde(default = "default_period")]
+    pub period: u64,
+}
+
+fn default_period() -> u64 {
+    24 * 60 * 60
+}
+
+// Users from a JSON credentials file, reloaded when the file changes.
+pub struct Credentials {
+    path: PathBuf,
+    users: RwLock<HashMap<String, User>>,
+    modified: Mutex<Option<SystemTime>>,
+    // PBKDF2 is slow on purpose, so passwords which already matched aren't hashed again
+    verified: Mutex<HashSet<Vec<u8>>>,
+    hashing: Semaphore,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> Result<Self> {
+        let credentials = Credentials {
+            path: path.to_owned(),
+            users: RwLock::new(HashMap::new()),
+            modified: Mutex::new(None),
+            verified: Mutex::new(HashSet::new()),
+            hashing: Semaphore::new(MAX_CONCURRENT_HASHES),
+        };
+
+        credentials.reload()?;
+
+        Ok(credentials)
+    }
+
+    pub async fn watch(&self) -> Result<()> {
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+
+            let modified = std::fs::metadata(&self.path)
+                .and_then(|m| m.modified())
+                .ok();
+
+            if modified == *self.modified.lock().unwrap() {
+                continue;
+            }
+
+            match self.reload() {
+                Ok(()) => info!("reloaded credentials from {:?}", self.path),
+                Err(e) => {
+                    warn!("failed to reload credentials. previous users are kept.");
+                    debug!("{:?}", e);
+
+                    // warn once per change
+                    *self.modified.lock().unwrap() = modified;
+                }
+            }
+        }
+    }
+
+    fn reload(&self) -> Result<()> {
+        let modified = std::fs::metadata(&self.path)?.modified().ok();
+        let file: CredentialsFile = serde_json::from_slice(&std::fs::read(&self.path)?)?;
+        let mut users = HashMap::new();
+
+        for user in file.users {
+            PasswordHash::parse(&user.password_hash)?;
+
+            if user.hops == Some(0) {
+                bail!("user {} needs at least 1 hop", user.username)
+            }
+
+            if matches!(user.hops, Some(hops) if hops > MAX_HOPS) {
+                bail!(
+                    "user {} can't have more than {} hops",
+                    user.username,
+                    MAX_HOPS
+                )
+            }
+
+            if user.period == 0 {
+                bail!("user {} needs a period of at least 1 second", user.username)
+            }
+
+            if users.insert(user.username.clone(), user).is_some() {
+                bail!("duplicated user in {:?}", self.path)
+            }
+        }
+
+        info!("loaded {} users", users.len());
+
+        *self.users.write().unwrap() = users;
+        *self.modified.lock().unwrap() = modified;
+        self.verified.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<User> {
+        let user = self.users.read().unwrap().get(username).cloned();
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                // as slow as a wrong password, so usernames can't be probed
+                let password = password.to_owned();
+                let _ = self.hash(move || PasswordHash::new(&password)).await?;
+                return Err(proxy_auth_required("authorization failure (unknown user)"));
+            }
+        };
+
+        let fingerprint = hash(
+            MessageDigest::sha256(),
+            format!("{}\0{}\0{}", username, password, user.password_hash).as_bytes(),
+        )?
+        .to_vec();
+
+        if self.verified.lock().unwrap().contains(&fingerprint) {
+            return Ok(user);
+        }
+
+        let password_hash = PasswordHash::parse(&user.password_hash)?;
+        let password = password.to_owned();
+
+        if !self.hash(move || password_hash.verify(&password)).await? {
+            return Err(proxy_auth_required(
+                "authorization failure (invalid password)",
+            ));
+        }
+
+        self.verified.lock().unwrap().insert(fingerprint);
+
+        Ok(user)
+    }
+
+    async fn hash<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        let _permit = self.hashing.acquire().await?;
+
+        tokio::task::spawn_blocking(f).await?
+    }
+}
+
+// `pbkdf2-sha256$<iterations>$<base64 salt>$<base64 key>`
+struct PasswordHash {
+    iterations: usize,
+    salt: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl PasswordHash {
+    fn new(password: &str) -> Result<Self> {
+        let mut salt = vec![0; SALT_LEN];
+        rand_bytes(&mut salt)?;
+
+        let key = PasswordHash::derive(password, &salt, HASH_ITERATIONS)?;
+
+        Ok(PasswordHash {
+            iterations: HASH_ITERATIONS,
+            salt,
+            key,
+        })
+    }
+
+    fn parse(encoded: &str) -> Result<Self> {
+        let parts: Vec<&str> = encoded.split('$').collect();
+
+        if parts.len() != 4 || parts[0] != HASH_SCHEME {
+            bail!("unsupported password hash. create one with `hash-password`.")
+        }
+
+        Ok(PasswordHash {
+            iterations: parts[1].parse()?,
+            salt: base64::decode(parts[2])?,
+            key: base64::decode(parts[3])?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!(
+            "{}${}${}${}",
+            HASH_SCHEME,
+            self.iterations,
+            base64::encode(&self.salt),
+            base64::encode(&self.key)
+        )
+    }
+
+    fn verify(&self, password: &str) -> Result<bool> {
+        let key = PasswordHash::derive(password, &self.salt, self.iterations)?;
+
+        Ok(key.len() == self.key.len() && memcmp::eq(&key, &self.key))
+    }
+
+    fn derive(password: &str, salt: &[u8], iterations: usize) -> Result<Vec<u8>> {
+        let mut key = vec![0; KEY_LEN];
+
+        pbkdf2_hmac(
+            password.as_bytes(),
+            salt,
+            iterations,
+            MessageDigest::sha256(),
+            &mut key,
+        )?;
+
+        Ok(key)
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    Ok(PasswordHash::new(password)?.encode())
+}
+
+// How clients prove who they are. With neither a token nor credentials, the gateway is open.
+pub struct Auth {
+    pub token: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub realm: String,
+}
+
+impl Auth {
+    pub fn is_required(&self) -> bool {
+        self.token.is_some() || self.credentials.is_some()
+    }
+
+    // The token is accepted as a username with any password.
+    // Returns the user when authenticated against the credentials file.
+    pub async fn authenticate(&self, username: &str, password: &str) -> Result<Option<User>> {
+        if !self.is_required() {
+            return Ok(None);
+        }
+
+        if let Some(token) = &self.token {
+            if memcmp_str(username, token) {
+                return Ok(None);
+            }
+        }
+
+        match &self.credentials {
+            Some(credentials) => Ok(Some(credentials.authenticate(username, password).await?)),
+            None => Err(proxy_auth_required("authorization failure (invalid token)")),
+        }
+    }
+}
+
+fn memcmp_str(a: &str, b: &str) -> bool {
+    a.len() == b.len() && memcmp::eq(a.as_bytes(), b.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(token: Option<&str>, credentials: Option<Credentials>) -> Auth {
+        Auth {
+            token: token.map(String::from),
+            credentials,
+            realm: "negy".to_owned(),
+        }
+    }
+
+    #[test]
+    fn password_hash_roundtrip() {
+        let encoded = hash_password("correct horse").unwrap();
+        let parsed = PasswordHash::parse(&encoded).unwrap();
+
+        assert!(encoded.starts_with("pbkdf2-sha256$100000$"));
+        assert_eq!(parsed.iterations, HASH_ITERATIONS);
+        assert_eq!(parsed.salt.len(), SALT_LEN);
+        assert_eq!(parsed.key.len(), KEY_LEN);
+        assert_eq!(parsed.encode(), encoded);
+        assert!(parsed.verify("correct horse").unwrap());
+    }
+
+    #[test]
+    fn password_hash_wrong_password() {
+        let parsed = PasswordHash::parse(&hash_password("correct horse").unwrap()).unwrap();
+
+        assert!(!parsed.verify("battery staple").unwrap());
+        assert!(!parsed.verify("").unwrap());
+    }
+
+    #[test]
+    fn password_hash_reject_unknown_scheme() {
+        let encoded = hash_password("correct horse").unwrap();
+
+        assert!(PasswordHash::parse(&encoded.replacen("pbkdf2-sha256", "bcrypt", 1)).is_err());
+        assert!(PasswordHash::parse("pbkdf2-sha256$100000$c2FsdA==").is_err());
+        assert!(PasswordHash::parse("plain text").is_err());
+    }
+
+    #[tokio::test]
+    async fn token_with_any_password() {
+        let auth = auth(Some("secret"), None);
+
+        assert!(auth.authenticate("secret", "").await.unwrap().is_none());
+        assert!(auth
+            .authenticate("secret", "anything")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(auth.authenticate("secret2", "secret").await.is_err());
+        assert!(auth.authenticate("", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn credentials_authenticate() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            format!(
+                r#"{{"users": [{{"username": "alice", "password_hash": "{}", "hops": 2}}]}}"#,
+                hash_password("correct horse").unwrap()
+            ),
+        )
+        .unwrap();
+
+        let auth = auth(None, Some(Credentials::load(file.path()).unwrap()));
+
+        for _ in 0..2 {
+            let user = auth.authenticate("alice", "correct horse").await.unwrap();
+            assert_eq!(user.unwrap().hops, Some(2));
+        }
+
+        assert!(auth.authenticate("alice", "battery staple").await.is_err());
+        assert!(auth.authenticate("bob", "correct horse").await.is_err());
+    }
+}
@@ -0,0 +1,921 @@
+use crate::filter::NodeMatcher;
+use crate::gateway::NodeUnselected;
+use crate::selection::{self, PathSelection};
+use anyhow::{bail, Result};
+use bytes::{BufMut, BytesMut};
+use negy_common::aes::{Aes, Direction};
+use negy_common::cell::Cell;
+use negy_common::destination::Destination;
+use negy_common::encrypted_payload::EncryptedPayload;
+use negy_common::key_exchange::{self, KeyExchange, PUBLIC_KEY_LEN};
+use negy_common::oaep;
+use negy_common::policy::Policy;
+use negy_common::protocol::{HandshakeHeader, Protocol, Reply, ReplyStatus};
+use negy_common::stream::{Stream, Streams};
+use openssl::pkey::Public;
+use openssl::rsa::Rsa;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct HandshakeError {
+    // None when the destination failed rather than a node
+    pub node: Option<(usize, SocketAddr)>,
+    pub status: ReplyStatus,
+    pub message: String,
+}
+
+impl HandshakeError {
+    fn node<M: Display>(hop: usize, addr: SocketAddr, status: ReplyStatus, message: M) -> Self {
+        HandshakeError {
+            node: Some((hop, addr)),
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    fn destination<M: Display>(status: ReplyStatus, message: M) -> Self {
+        HandshakeError {
+            node: None,
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.node {
+            Some((hop, addr)) => write!(
+                f,
+                "handshake failed at hop #{} {} ({:?}): {}",
+                hop + 1,
+                addr,
+                self.status,
+                self.message
+            ),
+            None => write!(
+                f,
+                "handshake failed at destination ({:?}): {}",
+                self.status, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+pub struct Node {
+    rsa: Rsa<Public>,
+    dist: SocketAddr,
+    key_exchange: KeyExchange,
+    exit_policy: Option<Policy>,
+}
+
+impl Node {
+    fn new(n: &NodeUnselected) -> Result<Self> {
+        Ok(Node {
+            rsa: n.rsa.clone(),
+            dist: n.addr,
+            key_exchange: KeyExchange::new()?,
+            exit_policy: n.exit_policy.clone(),
+        })
+    }
+}
+
+// What a circuit is built for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Target<'a> {
+    // None for circuits built ahead of time
+    pub dist: Option<&'a Destination>,
+    // pinned by the client
+    pub entry_node: Option<&'a NodeMatcher>,
+    pub exit_node: Option<&'a NodeMatcher>,
+}
+
+// The last hop is an exit which accepts `target`, and the first is a pinned entry or a guard
+// when there are several hops.
+// No two hops are related, see `PathSelection::are_related`.
+pub fn select_nodes(
+    node_pool: &RwLock<Vec<NodeUnselected>>,
+    selection: &PathSelection,
+    hops: usize,
+    excluded: &[SocketAddr],
+    target: Target,
+) -> Result<Vec<Node>> {
+    if hops == 0 {
+        bail!("a circuit needs at least 1 hop")
+    }
+
+    // the only hop of such a circuit is its exit, which can't also be the requested entry
+    if hops == 1 {
+        if let Some(entry_node) = target.entry_node {
+            bail!(
+                "entry node {} can't be pinned on a circuit of 1 hop",
+                entry_node
+            )
+        }
+    }
+
+    let mut excluded = excluded.to_vec();
+    let mut path = Vec::new();
+    let mut nodes = Vec::new();
+
+    if hops > 1 && pins_entry(selection, target) {
+        let entry = select_entry(node_pool, selection, &excluded, target.entry_node)?;
+        excluded.push(entry.dist);
+        path.push(entry.dist);
+        nodes.push(entry);
+    }
+
+    let exit = select_exit(node_pool, selection, &excluded, &path, target)?;
+    excluded.push(exit.dist);
+    path.push(exit.dist);
+
+    nodes.extend(select_relays(
+        node_pool,
+        selection,
+        hops - 1 - nodes.len(),
+        &excluded,
+        &path,
+    )?);
+    nodes.push(exit);
+
+    Ok(nodes)
+}
+
+fn pins_entry(selection: &PathSelection, target: Target) -> bool {
+    target.entry_node.is_some() || selection.pins_entry()
+}
+
+// The entry pinned by the client, one of --entry-nodes if any, or a guard.
+fn select_entry(
+    node_pool: &RwLock<Vec<NodeUnselected>>,
+    selection: &PathSelection,
+    excluded: &[SocketAddr],
+    entry_node: Option<&NodeMatcher>,
+) -> Result<Node> {
+    let node_pool = node_pool.read().unwrap();
+    let matchers = match entry_node {
+        Some(entry_node) => std::slice::from_ref(entry_node),
+        None => &selection.entry_nodes[..],
+    };
+
+    if !matchers.is_empty() {
+        let candidates: Vec<&NodeUnselected> = node_pool
+            .iter()
+            .filter(|n| {
+                !excluded.contains(&n.addr)
+                    && n.role.is_relay()
+                    && matchers.iter().any(|m| m.matches(n))
+            })
+            .collect();
+
+        return match selection::choose(selection.strategy.as_ref(), &candidates, 1)?.first() {
+            Some(n) => Node::new(n),
+            None => match entry_node {
+                Some(entry_node) => bail!("no entry node matching {} available", entry_node),
+                None => bail!("none of --entry-nodes is available"),
+            },
+        };
+    }
+
+    let addr = selection
+        .guards
+        .pick(&node_pool, selection.strategy.as_ref(), excluded)?;
+
+    match node_pool.iter().find(|n| n.addr == addr) {
+        Some(n) => Node::new(n),
+        None => bail!("guard {} is not listed", addr),
+    }
+}
+
+// `path` is the other hops of the circuit, which the relays must not be related to.
+fn select_relays(
+    node_pool: &RwLock<Vec<NodeUnselected>>,
+    selection: &PathSelection,
+    count: usize,
+    excluded: &[SocketAddr],
+    path: &[SocketAddr],
+) -> Result<Vec<Node>> {
+    let node_pool = node_pool.read().unwrap();
+    let mut path: Vec<&NodeUnselected> = node_pool
+        .iter()
+        .filter(|n| path.contains(&n.addr))
+        .collect();
+    let candidates: Vec<&NodeUnselected> = node_pool
+        .iter()
+        .filter(|n| !excluded.contains(&n.addr) && n.role.is_relay())
+        .collect();
+    let mut random_selected_nodes: Vec<Node> = Vec::new();
+
+    // one by one, as each relay narrows down the next
+    while random_selected_nodes.len() < count {
+        let unrelated: Vec<&NodeUnselected> = candidates
+            .iter()
+            .copied()
+            .filter(|n| {
+                path.iter()
+                    .all(|p| p.addr != n.addr && !selection.are_related(p, n))
+            })
+            .collect();
+
+        let n = match selection::choose(selection.strategy.as_ref(), &unrelated, 1)?.first() {
+            Some(n) => *n,
+            None => bail!(
+                "not enough relay nodes unrelated to the other hops (needed {}, found {} among {} candidates)",
+                count,
+                random_selected_nodes.len(),
+                candidates.len()
+            ),
+        };
+
+        path.push(n);
+        random_selected_nodes.push(Node::new(n)?);
+    }
+
+    Ok(random_selected_nodes)
+}
+
+fn select_exit(
+    node_pool: &RwLock<Vec<NodeUnselected>>,
+    selection: &PathSelection,
+    excluded: &[SocketAddr],
+    path: &[SocketAddr],
+    target: Target,
+) -> Result<Node> {
+    let node_pool = node_pool.read().unwrap();
+    let path: Vec<&NodeUnselected> = node_pool
+        .iter()
+        .filter(|n| path.contains(&n.addr))
+        .collect();
+    let candidates: Vec<&NodeUnselected> = node_pool
+        .iter()
+        .filter(|n| {
+            !excluded.contains(&n.addr)
+                && n.can_exit_to(target.dist)
+                && selection.allows_exit(n)
+                && target.exit_node.map(|m| m.matches(n)).unwrap_or(true)
+        })
+        .collect();
+    let unrelated: Vec<&NodeUnselected> = candidates
+        .iter()
+        .copied()
+        .filter(|n| path.iter().all(|p| !selection.are_related(p, n)))
+        .collect();
+
+    match selection::choose(selection.strategy.as_ref(), &unrelated, 1)?.first() {
+        Some(n) => Node::new(n),
+        None if !candidates.is_empty() => bail!(
+            "every exit node available ({}) is related to another hop of the circuit",
+            candidates.len()
+        ),
+        None => match (target.exit_node, target.dist) {
+            (Some(exit_node), Some(dist)) => {
+                bail!("no exit node matching {} accepts {}", exit_node, dist)
+            }
+            (Some(exit_node), None) => bail!("no exit node matching {} available", exit_node),
+            (None, Some(dist)) => bail!("no exit node accepts {}", dist),
+            (None, None) => bail!("no exit node available"),
+        },
+    }
+}
+
+struct Hop {
+    aes: Aes,
+    encrypted_payload: EncryptedPayload,
+}
+
+// Onion layers of a circuit, the first hop is the outermost layer.
+struct Layers {
+    hops: Vec<Hop>,
+}
+
+impl Layers {
+    fn seal(&mut self, payload: &[u8]) -> Result<BytesMut> {
+        let mut payload = BytesMut::from(payload);
+
+        for hop in self.hops.iter_mut().rev() {
+            let encrypted = hop.aes.encrypt(&payload)?;
+            payload = EncryptedPayload::encode(&encrypted)?;
+        }
+
+        Ok(payload)
+    }
+
+    // Returns every message sent by the exit which is complete so far.
+    fn open(&mut self, payload: &[u8]) -> Result<Vec<BytesMut>> {
+        let mut payloads = vec![BytesMut::from(payload)];
+
+        for hop in self.hops.iter_mut() {
+            let mut decrypted_payloads = Vec::new();
+
+            for payload in payloads {
+                for p in hop.encrypted_payload.read(&payload)? {
+                    decrypted_payloads.push(BytesMut::from(&hop.aes.decrypt(&p)? as &[u8]));
+                }
+            }
+
+            payloads = decrypted_payloads;
+        }
+
+        Ok(payloads)
+    }
+}
+
+pub struct Circuit {
+    upstream: TcpStream,
+    layers: Layers,
+    created_at: Instant,
+    exit_policy: Option<Policy>,
+}
+
+impl Circuit {
+    pub async fn build(
+        mut nodes: Vec<Node>,
+        node_pool: &RwLock<Vec<NodeUnselected>>,
+        selection: &PathSelection,
+        retries: usize,
+        target: Target<'_>,
+    ) -> Result<Circuit> {
+        let mut excluded: Vec<SocketAddr> = Vec::new();
+        let mut attempt = 0;
+
+        loop {
+            let e = match Circuit::handshake(&nodes).await {
+                Ok(circuit) => return Ok(circuit),
+                Err(e) => e,
+            };
+
+            let failed = e.downcast_ref::<HandshakeError>();
+
+            // a guard which can't be connected to is skipped for a while
+            if let Some(HandshakeError {
+                node: Some((0, addr)),
+                status: ReplyStatus::Timeout | ReplyStatus::UpstreamConnectFailed,
+                ..
+            }) = failed
+            {
+                selection.guards.mark_unreachable(addr);
+            }
+
+            match failed.and_then(|e| e.node) {
+                Some((hop, addr)) if attempt < retries => {
+                    attempt += 1;
+                    warn!(
+                        "{}. retrying with another node ({}/{})",
+                        e, attempt, retries
+                    );
+
+                    excluded.push(addr);
+                    Circuit::replace_node(
+                        &mut nodes, hop, node_pool, selection, &excluded, target,
+                    )?;
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
+    fn replace_node(
+        nodes: &mut Vec<Node>,
+        hop: usize,
+        node_pool: &RwLock<Vec<NodeUnselected>>,
+        selection: &PathSelection,
+        excluded: &[SocketAddr],
+        target: Target,
+    ) -> Result<()> {
+        // the next entry may be one of the other hops, so the circuit is selected again
+        if hop == 0 && nodes.len() > 1 && pins_entry(selection, target) {
+            *nodes = select_nodes(node_pool, selection, nodes.len(), excluded, target)?;
+            return Ok(());
+        }
+
+        let path: Vec<SocketAddr> = nodes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != hop)
+            .map(|(_, n)| n.dist)
+            .collect();
+
+        let mut excluded = excluded.to_vec();
+        excluded.extend(nodes.iter().map(|n| n.dist));
+
+        nodes[hop] = if hop == nodes.len() - 1 {
+            select_exit(node_pool, selection, &excluded, &path, target)?
+        } else {
+            select_relays(node_pool, selection, 1, &excluded, &path)?.remove(0)
+        };
+
+        // never reuse ephemeral keys across attempts
+        for n in nodes.iter_mut() {
+            n.key_exchange = KeyExchange::new()?;
+        }
+
+        Ok(())
+    }
+
+    // The exit gets an empty destination and opens a connection per stream instead,
+    // so circuits can be built before any destination is known.
+    async fn handshake(nodes: &[Node]) -> Result<Circuit> {
+        let first = nodes.first().unwrap().dist;
+
+        let mut upstream = timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(first))
+            .await
+            .map_err(|_| HandshakeError::node(0, first, ReplyStatus::Timeout, "connect timeout"))?
+            .map_err(|e| HandshakeError::node(0, first, ReplyStatus::UpstreamConnectFailed, e))?;
+        let (mut u_rx, mut u_tx) = upstream.split();
+
+        let mut payload = BytesMut::new();
+        let mut dist = String::new();
+
+        for n in nodes.iter().rev() {
+            let encrypted_dist = oaep::encrypt(&n.rsa, dist.as_bytes())?;
+
+            let mut hop_header = BytesMut::new();
+            hop_header.extend_from_slice(&encrypted_dist);
+            hop_header.extend_from_slice(&n.key_exchange.public_key()?);
+
+            let header = HandshakeHeader::new(hop_header.len(), hop_header.len() + payload.len())?;
+
+            let mut bytes = BytesMut::new();
+            bytes.put_u8(Protocol::Tunnel.symbol_byte());
+            header.encode(&mut bytes);
+            bytes.extend_from_slice(&hop_header);
+            bytes.extend_from_slice(&payload);
+            payload = bytes;
+            dist = n.dist.to_string();
+        }
+
+        u_tx.write_all(&payload)
+            .await
+            .map_err(|e| HandshakeError::node(0, first, ReplyStatus::UpstreamConnectFailed, e))?;
+
+        let reply = timeout(HANDSHAKE_TIMEOUT, EncryptedPayload::read_frame(&mut u_rx))
+            .await
+            .map_err(|_| HandshakeError::node(0, first, ReplyStatus::Timeout, "reply timeout"))?
+            .map_err(|e| HandshakeError::node(0, first, ReplyStatus::UpstreamProtocolError, e))?;
+        let reply = Reply::parse(&reply)
+            .map_err(|e| HandshakeError::node(0, first, ReplyStatus::UpstreamProtocolError, e))?;
+
+        let hops = Circuit::verify_reply(nodes, reply)?;
+
+        Ok(Circuit {
+            upstream,
+            layers: Layers { hops },
+            created_at: Instant::now(),
+            exit_policy: nodes.last().unwrap().exit_policy.clone(),
+        })
+    }
+
+    // Each reply embeds the reply of its successor sealed with the hop's key,
+    // so it's opened only once the hop is verified and its key is derived.
+    fn verify_reply(nodes: &[Node], mut reply: Reply) -> Result<Vec<Hop>> {
+        let mut hops = Vec::new();
+
+        for (i, n) in nodes.iter().enumerate() {
+            if reply.status != ReplyStatus::Ok {
+                // the exit doesn't connect to the destination during the handshake,
+                // so upstream failures are always about its successor
+                let e = match nodes.get(i + 1) {
+                    Some(successor) if reply.status.is_upstream_failure() => {
+                        HandshakeError::node(i + 1, successor.dist, reply.status, &reply.message)
+                    }
+                    _ => HandshakeError::node(i, n.dist, reply.status, &reply.message),
+                };
+
+                return Err(e.into());
+            }
+
+            if reply.public_key.len() != PUBLIC_KEY_LEN {
+                return Err(HandshakeError::node(
+                    i,
+                    n.dist,
+                    ReplyStatus::ProtocolError,
+                    "invalid public key length",
+                )
+                .into());
+            }
+
+            let mut node_public_key = [0; PUBLIC_KEY_LEN];
+            node_public_key.copy_from_slice(&reply.public_key);
+
+            let transcript = key_exchange::transcript(
+                &n.key_exchange.public_key()?,
+                &node_public_key,
+                &n.rsa.public_key_to_der()?,
+            );
+
+            key_exchange::verify(&n.rsa, &transcript, &reply.signature)
+                .map_err(|e| HandshakeError::node(i, n.dist, ReplyStatus::ProtocolError, e))?;
+
+            let key = n.key_exchange.derive(&node_public_key, &transcript)?;
+
+            let mut aes = Aes::import(&key, Direction::Forward);
+            let successor = std::mem::take(&mut reply.successor);
+
+            match nodes.get(i + 1) {
+                Some(_) if successor.is_empty() => {
+                    return Err(HandshakeError::node(
+                        i,
+                        n.dist,
+                        ReplyStatus::UpstreamProtocolError,
+                        "missing successor reply",
+                    )
+                    .into())
+                }
+                Some(next) => {
+                    let successor = aes.decrypt(&successor).map_err(|e| {
+                        HandshakeError::node(i, n.dist, ReplyStatus::ProtocolError, e)
+                    })?;
+
+                    reply = Reply::parse(&successor).map_err(|e| {
+                        HandshakeError::node(
+                            i + 1,
+                            next.dist,
+                            ReplyStatus::UpstreamProtocolError,
+                            e,
+                        )
+                    })?;
+                }
+                None if !successor.is_empty() => {
+                    return Err(HandshakeError::node(
+                        i,
+                        n.dist,
+                        ReplyStatus::UpstreamProtocolError,
+                        "unexpected successor reply",
+                    )
+                    .into())
+                }
+                None => {}
+            }
+
+            hops.push(Hop {
+                aes,
+                encrypted_payload: EncryptedPayload::new(),
+            });
+        }
+
+        Ok(hops)
+    }
+
+    // Hands the circuit to a task which carries the cells of every stream opened on the returned handle.
+    // The task ends when the upstream closes or when neither handles nor streams are left.
+    pub fn spawn(self) -> CircuitHandle {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let streams = Streams::new();
+
+        let handle = CircuitHandle {
+            tx,
+            streams: streams.clone(),
+            next_stream_id: Arc::new(AtomicU32::new(1)),
+            created_at: self.created_at,
+            exit_policy: Arc::new(self.exit_policy.clone()),
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = self.run(rx, &streams).await {
+                debug!("circuit closed {:?}", e);
+            }
+
+            streams.close();
+        });
+
+        handle
+    }
+
+    async fn run(mut self, mut rx: UnboundedReceiver<Cell>, streams: &Streams) -> Result<()> {
+        let mut u_bytes = [0; 4096];
+        let (mut u_rx, mut u_tx) = self.upstream.split();
+
+        loop {
+            tokio::select! {
+                n = u_rx.read(&mut u_bytes) => {
+                    let n = n?;
+
+                    if n == 0 {
+                        return Ok(());
+                    }
+
+                    for payload in self.layers.open(&u_bytes[..n])? {
+                        streams.dispatch(Cell::parse(&payload)?)?;
+                    }
+                }
+                cell = rx.recv() => {
+                    match cell {
+                        Some(cell) => {
+                            let payload = self.layers.seal(&cell.encode()?)?;
+
+                            u_tx.write_all(&payload).await?;
+                        }
+                        None => return Ok(()),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CircuitHandle {
+    tx: UnboundedSender<Cell>,
+    streams: Streams,
+    next_stream_id: Arc<AtomicU32>,
+    created_at: Instant,
+    exit_policy: Arc<Option<Policy>>,
+}
+
+impl CircuitHandle {
+    // Asks the exit to resolve and connect to `dist` on a new stream.
+    pub async fn open(&self, dist: &Destination) -> Result<Stream> {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let mut stream = self.streams.register(stream_id, self.tx.clone())?;
+
+        stream.send(Cell::Open {
+            stream_id,
+            dist: dist.to_string(),
+        })?;
+
+        let opened = timeout(HANDSHAKE_TIMEOUT, stream.recv())
+            .await
+            .map_err(|_| HandshakeError::destination(ReplyStatus::Timeout, "open timeout"))?;
+
+        match opened {
+            Some(Cell::Opened {
+                status: ReplyStatus::Ok,
+                resolved,
+                ..
+            }) => {
+                debug!("stream #{} opened to {} ({:?})", stream_id, dist, resolved);
+                Ok(stream)
+            }
+            Some(Cell::Opened {
+                status, message, ..
+            }) => Err(HandshakeError::destination(status, message).into()),
+            Some(cell) => bail!("Protocol error. Unexpected cell {:?}.", cell),
+            None => bail!("circuit is closed"),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    fn is_usable(&self, max_age: Duration) -> bool {
+        !self.streams.is_closed() && self.age() < max_age
+    }
+
+    fn can_exit_to(&self, dist: &Destination) -> bool {
+        match self.exit_policy.as_ref() {
+            Some(exit_policy) => exit_policy.check(&dist.host, None, dist.port).is_ok(),
+            None => false,
+        }
+    }
+}
+
+// Circuits built ahead of time and shared by clients, so a new client only waits for `CircuitHandle::open`.
+// Expired circuits take no new streams but live on until their last stream ends.
+pub struct CircuitPool {
+    circuits: Mutex<Vec<CircuitHandle>>,
+    size: usize,
+    max_age: Duration,
+}
+
+impl CircuitPool {
+    pub fn new(size: usize, max_age: Duration) -> Self {
+        CircuitPool {
+            circuits: Mutex::new(Vec::new()),
+            size,
+            max_age,
+        }
+    }
+
+    // The least busy circuit whose exit accepts `dist`
+    pub fn get(&self, dist: &Destination) -> Option<CircuitHandle> {
+        self.circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.is_usable(self.max_age) && c.can_exit_to(dist))
+            .min_by_key(|c| c.streams.len())
+            .cloned()
+    }
+
+    pub async fn maintain(
+        &self,
+        node_pool: Arc<RwLock<Vec<NodeUnselected>>>,
+        selection: Arc<PathSelection>,
+        hops: usize,
+        retries: usize,
+    ) -> Result<()> {
+        loop {
+            self.circuits
+                .lock()
+                .unwrap()
+                .retain(|c| c.is_usable(self.max_age));
+
+            while self.circuits.lock().unwrap().len() < self.size {
+                let target = Target::default();
+                let circuit = match select_nodes(&node_pool, &selection, hops, &[], target) {
+                    Ok(nodes) => {
+                        Circuit::build(nodes, &node_pool, &selection, retries, target).await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                match circuit {
+                    Ok(circuit) => self.circuits.lock().unwrap().push(circuit.spawn()),
+                    Err(e) => {
+                        debug!("failed to build a circuit for the pool {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use negy_node_pool::req::NodeRole;
+
+    fn relay(addr: &str) -> NodeUnselected {
+        NodeUnselected {
+            role: NodeRole::Relay,
+            ..NodeUnselected::for_test(addr)
+        }
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn select_nodes_unrelated_hops() {
+        let selection = PathSelection::for_test(true);
+        let node_pool = RwLock::new(vec![
+            NodeUnselected::for_test("10.0.0.1:3000"),
+            NodeUnselected::for_test("10.0.0.2:3000"),
+            NodeUnselected::for_test("10.1.0.1:3000"),
+            NodeUnselected::for_test("10.1.0.2:3000"),
+            NodeUnselected::for_test("10.2.0.1:3000"),
+            NodeUnselected::for_test("10.2.0.2:3000"),
+        ]);
+
+        for _ in 0..20 {
+            let nodes = select_nodes(&node_pool, &selection, 3, &[], Target::default()).unwrap();
+            let mut subnets: Vec<u8> = nodes
+                .iter()
+                .map(|n| match n.dist.ip() {
+                    std::net::IpAddr::V4(ip) => ip.octets()[1],
+                    _ => unreachable!(),
+                })
+                .collect();
+            subnets.sort_unstable();
+
+            assert_eq!(subnets, [0, 1, 2]);
+        }
+
+        assert!(select_nodes(&node_pool, &selection, 4, &[], Target::default()).is_err());
+    }
+
+    #[test]
+    fn select_relays_skip_related() {
+        let selection = PathSelection::for_test(true);
+        let node_pool = RwLock::new(vec![
+            relay("10.0.0.1:3000"),
+            relay("10.0.0.2:3000"),
+            relay("10.1.0.1:3000"),
+        ]);
+
+        for _ in 0..10 {
+            let relays =
+                select_relays(&node_pool, &selection, 1, &[], &[addr("10.0.0.1:3000")]).unwrap();
+
+            assert_eq!(relays[0].dist, addr("10.1.0.1:3000"));
+        }
+
+        let e = select_relays(&node_pool, &selection, 2, &[], &[addr("10.0.0.1:3000")])
+            .err()
+            .unwrap();
+
+        assert!(e
+            .to_string()
+            .starts_with("not enough relay nodes unrelated to the other hops (needed 2, found 1"));
+    }
+
+    #[test]
+    fn select_exit_skip_related() {
+        let selection = PathSelection::for_test(true);
+        let node_pool = RwLock::new(vec![
+            relay("10.0.0.1:3000"),
+            NodeUnselected::for_test("10.0.0.2:3000"),
+        ]);
+        let path = [addr("10.0.0.1:3000")];
+
+        let e = select_exit(&node_pool, &selection, &path, &path, Target::default())
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            e.to_string(),
+            "every exit node available (1) is related to another hop of the circuit"
+        );
+
+        node_pool
+            .write()
+            .unwrap()
+            .push(NodeUnselected::for_test("10.1.0.1:3000"));
+
+        let exit = select_exit(&node_pool, &selection, &path, &path, Target::default()).unwrap();
+
+        assert_eq!(exit.dist, addr("10.1.0.1:3000"));
+    }
+
+    #[test]
+    fn select_exit_none_available() {
+        let selection = PathSelection::for_test(true);
+        let node_pool = RwLock::new(vec![relay("10.0.0.1:3000")]);
+
+        let e = select_exit(&node_pool, &selection, &[], &[], Target::default())
+            .err()
+            .unwrap();
+
+        assert_eq!(e.to_string(), "no exit node available");
+    }
+
+    #[test]
+    fn select_nodes_entry_hint_over_entry_nodes() {
+        let mut selection = PathSelection::for_test(true);
+        selection.entry_nodes = vec!["10.0.0.1:3000".parse().unwrap()];
+
+        let node_pool = RwLock::new(vec![
+            NodeUnselected::for_test("10.0.0.1:3000"),
+            NodeUnselected::for_test("10.1.0.1:3000"),
+            NodeUnselected::for_test("10.2.0.1:3000"),
+        ]);
+        let entry_node: NodeMatcher = "10.1.0.1:3000".parse().unwrap();
+        let target = Target {
+            entry_node: Some(&entry_node),
+            ..Target::default()
+        };
+
+        for _ in 0..10 {
+            assert_eq!(
+                select_nodes(&node_pool, &selection, 2, &[], Target::default()).unwrap()[0].dist,
+                addr("10.0.0.1:3000")
+            );
+            assert_eq!(
+                select_nodes(&node_pool, &selection, 2, &[], target).unwrap()[0].dist,
+                addr("10.1.0.1:3000")
+            );
+        }
+
+        let missing: NodeMatcher = "10.9.0.1:3000".parse().unwrap();
+        let target = Target {
+            entry_node: Some(&missing),
+            ..Target::default()
+        };
+        let e = select_nodes(&node_pool, &selection, 2, &[], target)
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            e.to_string(),
+            "no entry node matching 10.9.0.1:3000 available"
+        );
+    }
+
+    #[test]
+    fn select_nodes_reject_entry_hint_on_single_hop() {
+        let selection = PathSelection::for_test(true);
+        let node_pool = RwLock::new(vec![NodeUnselected::for_test("10.0.0.1:3000")]);
+        let entry_node: NodeMatcher = "10.0.0.1:3000".parse().unwrap();
+        let target = Target {
+            entry_node: Some(&entry_node),
+            ..Target::default()
+        };
+
+        let e = select_nodes(&node_pool, &selection, 1, &[], target)
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            e.to_string(),
+            "entry node 10.0.0.1:3000 can't be pinned on a circuit of 1 hop"
+        );
+        assert!(select_nodes(&node_pool, &selection, 1, &[], Target::default()).is_ok());
+    }
+}
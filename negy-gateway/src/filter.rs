@@ -0,0 +1,198 @@
+use crate::gateway::NodeUnselected;
+use anyhow::{bail, Result};
+use negy_common::cidr::Cidr;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+// One node or a group of nodes. Matchers of an attribute a node doesn't have,
+// like the RDAP name of a node the node pool couldn't look up, never match it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeMatcher {
+    // `name:AMAZON-02`, the RDAP network name
+    Name(String),
+    // `country:JP`, the RDAP country code
+    Country(String),
+    // `203.0.113.0/24` or `2001:db8::/32`
+    Cidr(Cidr),
+    // `203.0.113.1:3000`
+    Addr(SocketAddr),
+    // `key:<fingerprint>`, the hex SHA-256 of the node's public key
+    Key(String),
+}
+
+impl NodeMatcher {
+    pub fn matches(&self, n: &NodeUnselected) -> bool {
+        match self {
+            NodeMatcher::Name(name) => matches!(&n.name, Some(n) if n.eq_ignore_ascii_case(name)),
+            NodeMatcher::Country(country) => {
+                matches!(&n.country, Some(c) if c.eq_ignore_ascii_case(country))
+            }
+            NodeMatcher::Cidr(cidr) => cidr.contains(&n.addr.ip()),
+            NodeMatcher::Addr(addr) => n.addr == *addr,
+            NodeMatcher::Key(fingerprint) => n.fingerprint == *fingerprint,
+        }
+    }
+}
+
+impl FromStr for NodeMatcher {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if let Some((kind, value)) = s.split_once(':') {
+            match kind.to_lowercase().as_str() {
+                "name" if !value.is_empty() => return Ok(NodeMatcher::Name(value.to_owned())),
+                "country" if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) => {
+                    return Ok(NodeMatcher::Country(value.to_uppercase()))
+                }
+                "key" if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    return Ok(NodeMatcher::Key(value.to_lowercase()))
+                }
+                "name" | "country" | "key" => bail!("invalid node matcher {:?}", s),
+                _ => {}
+            }
+        }
+
+        if let Ok(addr) = s.parse() {
+            return Ok(NodeMatcher::Addr(addr));
+        }
+
+        match s.parse() {
+            Ok(cidr) => Ok(NodeMatcher::Cidr(cidr)),
+            Err(_) => bail!(
+                "invalid node matcher {:?} (name:<rdap name>, country:<code>, key:<fingerprint>, a CIDR or an address)",
+                s
+            ),
+        }
+    }
+}
+
+impl Display for NodeMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeMatcher::Name(name) => write!(f, "name:{}", name),
+            NodeMatcher::Country(country) => write!(f, "country:{}", country),
+            NodeMatcher::Cidr(cidr) => write!(f, "{}", cidr),
+            NodeMatcher::Addr(addr) => write!(f, "{}", addr),
+            NodeMatcher::Key(fingerprint) => write!(f, "key:{}", fingerprint),
+        }
+    }
+}
+
+// A node is used when it matches any `include` matcher, or `include` is empty,
+// and matches no `exclude` matcher. Excluding wins.
+#[derive(Debug, Clone, Default)]
+pub struct NodeFilter {
+    include: Vec<NodeMatcher>,
+    exclude: Vec<NodeMatcher>,
+}
+
+impl NodeFilter {
+    pub fn parse<S: AsRef<str>>(include: &[S], exclude: &[S]) -> Result<Self> {
+        Ok(NodeFilter {
+            include: parse_matchers(include)?,
+            exclude: parse_matchers(exclude)?,
+        })
+    }
+
+    pub fn allows(&self, n: &NodeUnselected) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|m| m.matches(n)))
+            && !self.exclude.iter().any(|m| m.matches(n))
+    }
+
+    pub fn include(&self) -> &[NodeMatcher] {
+        &self.include
+    }
+
+    pub fn exclude(&self) -> &[NodeMatcher] {
+        &self.exclude
+    }
+}
+
+// Entries may also be comma-separated.
+pub fn parse_matchers<S: AsRef<str>>(matchers: &[S]) -> Result<Vec<NodeMatcher>> {
+    matchers
+        .iter()
+        .flat_map(|m| m.as_ref().split(','))
+        .filter(|m| !m.trim().is_empty())
+        .map(|m| m.parse())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(addr: &str, name: Option<&str>, country: Option<&str>) -> NodeUnselected {
+        NodeUnselected {
+            name: name.map(String::from),
+            country: country.map(String::from),
+            ..NodeUnselected::for_test(addr)
+        }
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> NodeFilter {
+        NodeFilter::parse(include, exclude).unwrap()
+    }
+
+    #[test]
+    fn filter_exclude_network() {
+        let f = filter(&[], &["name:AMAZON-02"]);
+
+        assert!(!f.allows(&node("203.0.113.1:3000", Some("AMAZON-02"), None)));
+        assert!(!f.allows(&node("203.0.113.1:3000", Some("amazon-02"), None)));
+        assert!(f.allows(&node("203.0.113.1:3000", Some("GOOGLE"), None)));
+        assert!(f.allows(&node("203.0.113.1:3000", None, None)));
+    }
+
+    #[test]
+    fn filter_include_only_listed() {
+        let f = filter(&["country:jp", "198.51.100.0/24"], &[]);
+
+        assert!(f.allows(&node("203.0.113.1:3000", None, Some("JP"))));
+        assert!(f.allows(&node("198.51.100.7:3000", None, None)));
+        assert!(!f.allows(&node("203.0.113.1:3000", None, Some("US"))));
+        assert!(!f.allows(&node("203.0.113.1:3000", None, None)));
+    }
+
+    #[test]
+    fn filter_exclude_wins() {
+        let f = filter(&["country:JP"], &["203.0.113.1:3000"]);
+
+        assert!(!f.allows(&node("203.0.113.1:3000", None, Some("JP"))));
+        assert!(f.allows(&node("203.0.113.1:3001", None, Some("JP"))));
+    }
+
+    #[test]
+    fn filter_match_key() {
+        let n = node("203.0.113.1:3000", None, None);
+        let f = filter(&[], &[&format!("key:{}", n.fingerprint.to_uppercase())]);
+
+        assert!(!f.allows(&n));
+        assert!(f.allows(&node("203.0.113.1:3000", None, None)));
+    }
+
+    #[test]
+    fn filter_parse() {
+        assert_eq!(
+            filter(&["name:A,name:B", ""], &[]).include(),
+            &[
+                NodeMatcher::Name("A".to_owned()),
+                NodeMatcher::Name("B".to_owned())
+            ]
+        );
+        assert_eq!(
+            "10.0.0.1".parse::<NodeMatcher>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert_eq!(
+            "[::1]:3000".parse::<NodeMatcher>().unwrap(),
+            NodeMatcher::Addr("[::1]:3000".parse().unwrap())
+        );
+        assert!("country:JPN".parse::<NodeMatcher>().is_err());
+        assert!("key:abc".parse::<NodeMatcher>().is_err());
+        assert!("AMAZON-02".parse::<NodeMatcher>().is_err());
+    }
+}
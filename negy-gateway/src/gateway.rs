@@ -1,273 +1,639 @@
+use crate::auth::{Auth, User};
+use crate::circuit::{select_nodes, Circuit, CircuitHandle, CircuitPool, HandshakeError, Target};
+use crate::hint::Hints;
+use crate::http::{self, RequestError};
+use crate::quota::{Metered, Quotas, Session};
+use crate::selection::PathSelection;
+use crate::socks5::{self, ReplyCode};
 use anyhow::{bail, Result};
-use bytes::{BufMut, BytesMut};
-use negy_common::aes::Aes;
-use negy_common::encrypted_payload::{EncryptedPayload, DELIMITER_LEN};
-use negy_common::protocol::Protocol;
+use bytes::BytesMut;
+use negy_common::destination::Destination;
+use negy_common::policy::Policy;
+use negy_common::protocol::{ReplyStatus, MAX_HOPS};
+use negy_common::stream::Stream;
+use negy_node_pool::req::NodeRole;
 use openssl::pkey::Public;
-use openssl::rsa::{Padding, Rsa};
-use rand::seq::SliceRandom;
-use std::net::{SocketAddr, ToSocketAddrs};
+use openssl::rsa::Rsa;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
+// Auto detects SOCKS5 from the first byte and falls back to HTTP.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Frontend {
+    Auto,
+    Http,
+    Socks5,
+}
+
+// Number of hops of circuits. Clients may ask for any count within `min..=max`.
+#[derive(Debug, Clone, Copy)]
+pub struct Hops {
+    pub default: usize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Hops {
+    // Without bounds, clients can't ask for another count than the default.
+    pub fn new(default: usize, min: Option<usize>, max: Option<usize>) -> Result<Self> {
+        let min = min.unwrap_or(default);
+        let max = max.unwrap_or(default);
+
+        if min == 0 {
+            bail!("a circuit needs at least 1 hop")
+        }
+
+        if max > MAX_HOPS {
+            bail!("a circuit can't have more than {} hops", MAX_HOPS)
+        }
+
+        if min > max {
+            bail!("--min-hops {} is more than --max-hops {}", min, max)
+        }
+
+        if !(min..=max).contains(&default) {
+            bail!(
+                "--hops {} is out of --min-hops {} and --max-hops {}",
+                default,
+                min,
+                max
+            )
+        }
+
+        Ok(Hops { default, min, max })
+    }
+
+    pub fn check(&self, requested: Option<usize>) -> Result<()> {
+        match requested {
+            Some(hops) if hops < self.min || hops > self.max => Err(http::forbidden(format!(
+                "{} hops requested, but {} to {} hops are allowed",
+                hops, self.min, self.max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct StateInit {
+    client: TcpStream,
+    auth: Arc<Auth>,
+    quotas: Arc<Quotas>,
+    policy: Arc<Policy>,
+    hops: Hops,
+    frontend: Frontend,
+}
+
 pub struct StateFetchNodes {
     client: TcpStream,
-    auth_token: Option<String>,
+    frontend: Frontend,
+    request: Request,
+    session: Option<Session>,
 }
 
 pub struct StateHandshake {
     client: TcpStream,
-    auth_token: Option<String>,
-    nodes: Vec<Node>,
+    frontend: Frontend,
+    request: Request,
+    session: Option<Session>,
+    circuit: Option<CircuitHandle>,
+    node_pool: Arc<RwLock<Vec<NodeUnselected>>>,
+    selection: Arc<PathSelection>,
+    hops: usize,
 }
 
 pub struct StateTunnel {
     client: TcpStream,
-    upstream: TcpStream,
-    nodes: Vec<Node>,
+    stream: Stream,
+    early_data: BytesMut,
+    body_remaining: Option<u64>,
+    session: Option<Session>,
+}
+
+struct Request {
+    dist: Destination,
+    // plain HTTP which is answered by the destination rather than the gateway
+    forwarded: bool,
+    // sent to the destination before anything else the client sends
+    early_data: BytesMut,
+    // bytes of the body still to come from the client when forwarded, after which it's cut off
+    body_remaining: Option<u64>,
+    // authenticated against the credentials file
+    user: Option<User>,
+    hints: Hints,
 }
 
 #[derive(Debug)]
 pub struct NodeUnselected {
     pub addr: SocketAddr,
     pub rsa: Rsa<Public>,
+    pub fingerprint: String,
+    // RDAP network name and country code
     pub name: Option<String>,
+    pub country: Option<String>,
     pub version: String,
+    pub role: NodeRole,
+    // kilobytes per second advertised by the node
+    pub bandwidth: Option<u64>,
+    // measured by the node pool
+    pub rtt_ms: Option<u64>,
+    // fingerprints of nodes the operator runs as well
+    pub family: Vec<String>,
+    // None when the node didn't advertise one, and then it's never the exit
+    pub exit_policy: Option<Policy>,
+}
+
+impl NodeUnselected {
+    // Without a destination, like for pooled circuits, the exit has to accept web traffic.
+    pub fn can_exit_to(&self, dist: Option<&Destination>) -> bool {
+        if !self.role.is_exit() {
+            return false;
+        }
+
+        match (&self.exit_policy, dist) {
+            (Some(exit_policy), Some(dist)) => {
+                exit_policy.check(&dist.host, None, dist.port).is_ok()
+            }
+            (Some(exit_policy), None) => {
+                exit_policy.accepts_port(80) || exit_policy.accepts_port(443)
+            }
+            (None, _) => false,
+        }
+    }
 }
 
-struct Node {
-    aes: Aes,
-    rsa: Rsa<Public>,
-    delimiter: [u8; DELIMITER_LEN],
-    dist: SocketAddr,
-    encrypted_payload: EncryptedPayload,
+#[cfg(test)]
+impl NodeUnselected {
+    // A relay and exit accepting any destination, with a fresh key.
+    pub fn for_test(addr: &str) -> Self {
+        let rsa = Rsa::generate(1024).unwrap();
+        let rsa = Rsa::public_key_from_pem(&rsa.public_key_to_pem().unwrap()).unwrap();
+
+        NodeUnselected {
+            addr: addr.parse().unwrap(),
+            fingerprint: negy_common::key_exchange::fingerprint(&rsa).unwrap(),
+            rsa,
+            name: None,
+            country: None,
+            version: "0.1.2".to_owned(),
+            role: NodeRole::Both,
+            bandwidth: None,
+            rtt_ms: None,
+            family: Vec::new(),
+            exit_policy: Some(Policy::parse(&["accept *"]).unwrap()),
+        }
+    }
 }
 
 pub struct Gateway<State> {
     state: State,
 }
 
-impl Gateway<StateFetchNodes> {
-    pub fn new(client: TcpStream, auth_token: Option<String>) -> Self {
+impl Gateway<StateInit> {
+    pub fn new(
+        client: TcpStream,
+        auth: Arc<Auth>,
+        quotas: Arc<Quotas>,
+        policy: Arc<Policy>,
+        hops: Hops,
+        frontend: Frontend,
+    ) -> Self {
         Gateway {
-            state: StateFetchNodes { client, auth_token },
+            state: StateInit {
+                client,
+                auth,
+                quotas,
+                policy,
+                hops,
+                frontend,
+            },
         }
     }
 
-    pub fn fetch_nodes(
-        self,
-        nodes: Arc<RwLock<Vec<NodeUnselected>>>,
-        hops: usize,
-    ) -> Result<Gateway<StateHandshake>> {
-        let mut rng = &mut rand::thread_rng();
-        let random_selected_nodes: Vec<Node> = nodes
-            .read()
-            .unwrap()
-            .choose_multiple(&mut rng, hops)
-            .into_iter()
-            .map(|n| Node {
-                aes: Aes::new(),
-                rsa: n.rsa.clone(),
-                delimiter: EncryptedPayload::new_delimiter(),
-                dist: n.addr,
-                encrypted_payload: EncryptedPayload::new(),
-            })
-            .collect();
-
-        if random_selected_nodes.len() < hops {
-            bail!(
-                "not enough nodes (hops={}, nodes={})",
-                hops,
-                random_selected_nodes.len()
-            )
-        }
+    pub async fn accept(mut self) -> Result<Gateway<StateFetchNodes>> {
+        let frontend = match self.state.frontend {
+            Frontend::Auto => self.detect_frontend().await?,
+            frontend => frontend,
+        };
+
+        let request = match frontend {
+            Frontend::Socks5 => self.parse_socks5().await,
+            _ => self.parse_http().await,
+        };
+
+        let (request, session) = match request
+            .and_then(|request| self.check_policy(request))
+            .and_then(|request| self.check_hops(request))
+            .and_then(|request| self.open_session(request))
+        {
+            Ok(opened) => opened,
+            Err(e) => {
+                let _ = self.response_error(frontend, &e).await;
+                return Err(e);
+            }
+        };
 
         Ok(Gateway {
-            state: StateHandshake {
+            state: StateFetchNodes {
                 client: self.state.client,
-                nodes: random_selected_nodes,
-                auth_token: self.state.auth_token,
+                frontend,
+                request,
+                session,
             },
         })
     }
-}
 
-impl Gateway<StateHandshake> {
-    pub async fn handshake(mut self) -> Result<Gateway<StateTunnel>> {
-        let addrs = self.parse_http().await?;
-
-        let mut u_bytes = [0; 4096];
-        let mut upstream = TcpStream::connect(self.state.nodes.first().unwrap().dist).await?;
-        let (mut u_rx, mut u_tx) = upstream.split();
-
-        let mut payload = BytesMut::new();
-        let mut dist = addrs[0];
-
-        for n in self.state.nodes.iter().rev() {
-            let mut encrypted_delimiter = vec![0; n.rsa.size() as usize];
-            n.rsa
-                .public_encrypt(&n.delimiter, &mut encrypted_delimiter, Padding::PKCS1)?;
-
-            let mut encrypted_dist = vec![0; n.rsa.size() as usize];
-            n.rsa.public_encrypt(
-                dist.to_string().as_bytes(),
-                &mut encrypted_dist,
-                Padding::PKCS1,
-            )?;
-
-            let mut encrypted_aes = vec![0; n.rsa.size() as usize];
-            n.rsa
-                .public_encrypt(&n.aes.get_key_iv(), &mut encrypted_aes, Padding::PKCS1)?;
-
-            let mut bytes = BytesMut::new();
-            bytes.put_u8(Protocol::Tunnel.symbol_byte());
-            bytes.extend_from_slice(&encrypted_delimiter);
-            bytes.extend_from_slice(&encrypted_dist);
-            bytes.extend_from_slice(&encrypted_aes);
-
-            let mut tmp = BytesMut::new();
-            tmp.extend_from_slice(&bytes);
-            tmp.extend_from_slice(&payload);
-            payload = tmp;
-            dist = n.dist;
+    async fn detect_frontend(&mut self) -> Result<Frontend> {
+        let mut first_byte = [0; 1];
+
+        if self.state.client.peek(&mut first_byte).await? == 0 {
+            bail!("client closed the connection")
+        }
+
+        if first_byte[0] == socks5::VERSION {
+            Ok(Frontend::Socks5)
+        } else {
+            Ok(Frontend::Http)
+        }
+    }
+
+    async fn parse_socks5(&mut self) -> Result<Request> {
+        let (dist, user, hints) = socks5::accept(&mut self.state.client, &self.state.auth).await?;
+
+        Ok(Request {
+            dist,
+            forwarded: false,
+            early_data: BytesMut::new(),
+            body_remaining: None,
+            user,
+            hints,
+        })
+    }
+
+    // Other methods than CONNECT are forwarded as plain HTTP, rewritten to origin-form.
+    async fn parse_http(&mut self) -> Result<Request> {
+        let mut buf = BytesMut::with_capacity(4096);
+        let head_len = http::read_head(&mut self.state.client, &mut buf).await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+
+        req.parse(&buf[..head_len])?;
+
+        let (user, mut hints) = self.authorize(&req).await?;
+        hints.merge_headers(req.headers)?;
+
+        let request = match req.method {
+            Some("CONNECT") => match req.path {
+                Some(path) => Request {
+                    dist: Destination::parse(path).map_err(http::bad_request)?,
+                    forwarded: false,
+                    early_data: BytesMut::from(&buf[head_len..]),
+                    body_remaining: None,
+                    user,
+                    hints,
+                },
+                None => {
+                    return Err(http::bad_request(
+                        "HTTP request target not found in your request.",
+                    ))
+                }
+            },
+            Some(_) => {
+                let (dist, mut early_data) = http::to_origin_form(&req)?;
+                let body_len = http::body_len(&req)?;
+
+                // anything read after the body is a pipelined request, which is dropped
+                let body = &buf[head_len..];
+                let body = &body[..body
+                    .len()
+                    .min(usize::try_from(body_len).unwrap_or(usize::MAX))];
+                early_data.extend_from_slice(body);
+
+                Request {
+                    dist,
+                    forwarded: true,
+                    early_data,
+                    body_remaining: Some(body_len - body.len() as u64),
+                    user,
+                    hints,
+                }
+            }
+            None => return Err(http::bad_request("HTTP method not found in your request.")),
+        };
+
+        Ok(request)
+    }
+
+    async fn authorize(&self, req: &httparse::Request<'_, '_>) -> Result<(Option<User>, Hints)> {
+        if !self.state.auth.is_required() {
+            return Ok((None, Hints::default()));
+        }
+
+        let auth = match req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+        {
+            Some(auth) => auth,
+            None => return Err(http::proxy_auth_required("authorization required")),
+        };
+
+        let credentials = auth
+            .value
+            .strip_prefix(b"Basic ")
+            .and_then(|v| base64::decode(v).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+
+        match credentials {
+            Some(credentials) => {
+                let (username, password) = credentials
+                    .split_once(':')
+                    .unwrap_or((credentials.as_str(), ""));
+                let (username, hints) = Hints::split_username(username)?;
+
+                Ok((
+                    self.state.auth.authenticate(username, password).await?,
+                    hints,
+                ))
+            }
+            None => Err(http::proxy_auth_required("invalid authorization header")),
         }
+    }
+
+    // Hostnames are resolved by the exit, so address rules only see IP literals here.
+    // Nodes check the resolved address against their own exit policy.
+    fn check_policy(&self, request: Request) -> Result<Request> {
+        let dist = &request.dist;
+
+        self.state
+            .policy
+            .check(&dist.host, None, dist.port)
+            .map_err(http::forbidden)?;
+
+        Ok(request)
+    }
+
+    // Hop counts of the credentials file aren't bounded, as they're set by the operator.
+    fn check_hops(&self, request: Request) -> Result<Request> {
+        self.state.hops.check(request.hints.hops)?;
 
-        u_tx.write_all(&payload).await?;
+        Ok(request)
+    }
 
-        let n = u_rx.read(&mut u_bytes).await?;
+    fn open_session(&self, request: Request) -> Result<(Request, Option<Session>)> {
+        let session = match &request.user {
+            Some(user) => Some(self.state.quotas.open(user)?),
+            None => None,
+        };
+
+        Ok((request, session))
+    }
 
-        if n != 2 && u_bytes[..2] != b"OK"[..] {
-            bail!("invalid response by upstream")
+    // SOCKS5 negotiation errors are answered while negotiating.
+    async fn response_error(&mut self, frontend: Frontend, e: &anyhow::Error) -> Result<()> {
+        let e = e.downcast_ref::<RequestError>();
+
+        if frontend == Frontend::Socks5 {
+            return match e {
+                Some(RequestError::Forbidden(_)) | Some(RequestError::TooManyRequests(_)) => {
+                    socks5::reply(&mut self.state.client, ReplyCode::NotAllowed).await
+                }
+                _ => Ok(()),
+            };
         }
 
-        self.response_200().await?;
+        match e {
+            Some(RequestError::BadRequest(_)) => {
+                http::write_response(&mut self.state.client, "400 Bad Request", "", "bad request")
+                    .await
+            }
+            Some(RequestError::ProxyAuthRequired(_)) => {
+                http::write_response(
+                    &mut self.state.client,
+                    "407 Proxy Authentication Required",
+                    &format!(
+                        "Proxy-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"\r\n",
+                        self.state.auth.realm
+                    ),
+                    "proxy authentication required",
+                )
+                .await
+            }
+            Some(RequestError::Forbidden(reason)) => {
+                http::write_response(&mut self.state.client, "403 Forbidden", "", reason).await
+            }
+            Some(RequestError::TooManyRequests(_)) => {
+                http::write_response(
+                    &mut self.state.client,
+                    "429 Too Many Requests",
+                    "Retry-After: 1\r\n",
+                    "too many requests",
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+}
 
+impl Gateway<StateFetchNodes> {
+    pub fn fetch_nodes(
+        self,
+        node_pool: Arc<RwLock<Vec<NodeUnselected>>>,
+        selection: Arc<PathSelection>,
+        circuit_pool: &CircuitPool,
+        hops: usize,
+    ) -> Result<Gateway<StateHandshake>> {
+        // the client's choice takes precedence over the user's one
+        let requested_hops = self
+            .state
+            .request
+            .hints
+            .hops
+            .or_else(|| self.state.request.user.as_ref().and_then(|user| user.hops));
+
+        // pooled circuits have the default number of hops and any entry and exit
+        let (hops, circuit) = match requested_hops {
+            Some(requested_hops) if requested_hops != hops => (requested_hops, None),
+            _ if self.state.request.hints.pins_node() => (hops, None),
+            _ => (hops, circuit_pool.get(&self.state.request.dist)),
+        };
+
+        // nodes are selected in `handshake` unless a pooled circuit is available
         Ok(Gateway {
-            state: StateTunnel {
+            state: StateHandshake {
                 client: self.state.client,
-                upstream,
-                nodes: self.state.nodes,
+                frontend: self.state.frontend,
+                request: self.state.request,
+                session: self.state.session,
+                circuit,
+                node_pool,
+                selection,
+                hops,
             },
         })
     }
+}
 
-    async fn parse_http(&mut self) -> Result<Vec<SocketAddr>> {
-        let mut c_bytes = [0; 4096];
-        let (mut c_rx, _) = self.state.client.split();
-        let n = c_rx.read(&mut c_bytes).await?;
+impl Gateway<StateHandshake> {
+    pub async fn handshake(mut self, retries: usize) -> Result<Gateway<StateTunnel>> {
+        let stream = match self.open_stream(retries).await {
+            Ok(established) => established,
+            Err(e) => {
+                let _ = self.response_error(&e).await;
+                return Err(e);
+            }
+        };
 
-        let req_raw = std::str::from_utf8(&c_bytes[..n])?;
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
+        self.response_ok().await?;
 
-        req.parse(req_raw.as_bytes())?;
+        Ok(Gateway {
+            state: StateTunnel {
+                client: self.state.client,
+                stream,
+                early_data: self.state.request.early_data,
+                body_remaining: self.state.request.body_remaining,
+                session: self.state.session,
+            },
+        })
+    }
 
-        match req.method {
-            Some("CONNECT") => {}
-            Some(method) => bail!("unsupported method {}. Only CONNECT is supported.", method),
-            None => bail!("HTTP method not found in your request."),
-        }
+    async fn open_stream(&mut self, retries: usize) -> Result<Stream> {
+        let dist = &self.state.request.dist;
 
-        if self.state.auth_token.is_some() {
-            if let Some(auth) = req
-                .headers
-                .into_iter()
-                .find(|h| h.name.to_lowercase() == "proxy-authorization")
-            {
-                if let Some(v) = auth.value.strip_prefix(b"Basic ") {
-                    let decoded = base64::decode(v)?;
-                    let token = std::str::from_utf8(&decoded)?
-                        .splitn(2, ":")
-                        .collect::<Vec<&str>>()[0];
-
-                    if Some(token.to_owned()) != self.state.auth_token {
-                        bail!("authorization failure (invalid token)")
-                    }
-                } else {
-                    bail!("invalid authorization header")
+        if let Some(circuit) = self.state.circuit.take() {
+            match circuit.open(dist).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) if e.downcast_ref::<HandshakeError>().is_some() => return Err(e),
+                Err(e) => {
+                    warn!("pooled circuit is broken. building a new one.");
+                    debug!("{:?}", e);
                 }
-            } else {
-                bail!("authorization token required")
             }
         }
 
-        Ok(req.path.unwrap().to_socket_addrs().unwrap().collect())
+        let node_pool = &self.state.node_pool;
+        let selection = &self.state.selection;
+
+        let target = Target {
+            dist: Some(dist),
+            entry_node: self.state.request.hints.entry_node.as_ref(),
+            exit_node: self.state.request.hints.exit_node.as_ref(),
+        };
+
+        let nodes = select_nodes(node_pool, selection, self.state.hops, &[], target)?;
+        let circuit = Circuit::build(nodes, node_pool, selection, retries, target).await?;
+
+        circuit.spawn().open(dist).await
     }
 
-    async fn response_200(&mut self) -> Result<()> {
+    async fn response_ok(&mut self) -> Result<()> {
+        if self.state.request.forwarded {
+            return Ok(());
+        }
+
+        if self.state.frontend == Frontend::Socks5 {
+            return socks5::reply(&mut self.state.client, ReplyCode::Succeeded).await;
+        }
+
         let (_, mut c_tx) = self.state.client.split();
 
         c_tx.write_all("HTTP/1.1 200 OK\r\n\r\n".as_bytes()).await?;
 
         Ok(())
     }
+
+    async fn response_error(&mut self, e: &anyhow::Error) -> Result<()> {
+        let e = e.downcast_ref::<HandshakeError>();
+
+        if self.state.frontend == Frontend::Socks5 {
+            let code = match e.map(|e| (e.status, e.node)) {
+                Some((ReplyStatus::Timeout, _)) => ReplyCode::TtlExpired,
+                Some((ReplyStatus::PolicyDenied, _)) => ReplyCode::NotAllowed,
+                Some((_, None)) => ReplyCode::HostUnreachable,
+                _ => ReplyCode::GeneralFailure,
+            };
+
+            return socks5::reply(&mut self.state.client, code).await;
+        }
+
+        let (status_line, reason) = match e {
+            Some(e) => match (e.status, e.node) {
+                (ReplyStatus::Timeout, _) => ("504 Gateway Timeout", "circuit timed out"),
+                (ReplyStatus::PolicyDenied, _) => ("403 Forbidden", "destination denied by policy"),
+                (_, Some(_)) => ("502 Bad Gateway", "failed to build circuit"),
+                (_, None) => ("502 Bad Gateway", "failed to connect to destination"),
+            },
+            None => ("502 Bad Gateway", "failed to build circuit"),
+        };
+
+        http::write_response(&mut self.state.client, status_line, "", reason).await
+    }
 }
 
 impl Gateway<StateTunnel> {
-    pub async fn tunnel(&mut self) -> Result<()> {
-        let mut c_bytes = [0; 4096];
-        let mut u_bytes = [0; 4096];
-
-        let (mut c_rx, mut c_tx) = self.state.client.split();
-        let (mut u_rx, mut u_tx) = self.state.upstream.split();
-
-        loop {
-            tokio::select! {
-                n = c_rx.read(&mut c_bytes) => {
-                    match n {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            let mut payload = BytesMut::from(&c_bytes[..n]);
-
-                            for node in self.state.nodes.iter().rev() {
-                                let encrypted = node.aes.encrypt(&payload)?;
-                                let mut tmp = BytesMut::from(&encrypted as &[u8]);
-                                tmp.extend_from_slice(&node.delimiter);
-                                payload = tmp;
-                            }
-
-                            u_tx.write_all(&payload).await?;
-                        },
-                        Err(e) => {
-                            error!("client read {:?}", e);
-                        }
-                    }
-                }
-                n = u_rx.read(&mut u_bytes) => {
-                    match n {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            let mut payload = BytesMut::from(&u_bytes[..n]);
-
-                            for node in self.state.nodes.iter_mut() {
-                                let payloads = node.encrypted_payload.read(&payload, &node.delimiter)?;
-                                let mut tmp = BytesMut::new();
-
-                                for p in payloads {
-                                    match node.aes.decrypt(&p) {
-                                        Ok(decrypted) => {
-                                            tmp.extend_from_slice(&decrypted)
-                                        },
-                                        Err(e) => {
-                                            return Err(e);
-                                        },
-                                    }
-                                }
-
-                                payload = tmp;
-                            }
-
-                            c_tx.write_all(&payload).await?;
-                        },
-                        Err(e) => {
-                            error!("upstream read {:?}", e);
-                        }
-                    }
-                }
+    // Users from the credentials file are metered, and cut off once their byte quota is used up.
+    pub async fn tunnel(self) -> Result<()> {
+        if !self.state.early_data.is_empty() {
+            if let Some(session) = &self.state.session {
+                session.record_sent(self.state.early_data.len())?;
             }
+
+            self.state.stream.write(&self.state.early_data).await?;
         }
 
-        Ok(())
+        let client = http::SingleRequest::new(self.state.client, self.state.body_remaining);
+
+        match self.state.session {
+            Some(session) => self.state.stream.pipe(Metered::new(client, session)).await,
+            None => self.state.stream.pipe(client).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hops_default_bounds() {
+        let hops = Hops::new(3, None, None).unwrap();
+
+        assert_eq!((hops.default, hops.min, hops.max), (3, 3, 3));
+        assert!(hops.check(None).is_ok());
+        assert!(hops.check(Some(3)).is_ok());
+        assert!(hops.check(Some(2)).is_err());
+        assert!(hops.check(Some(4)).is_err());
+    }
+
+    #[test]
+    fn hops_reject_invalid_bounds() {
+        assert!(Hops::new(3, Some(4), Some(2)).is_err());
+        assert!(Hops::new(3, Some(4), None).is_err());
+        assert!(Hops::new(3, None, Some(2)).is_err());
+        assert!(Hops::new(0, None, None).is_err());
+        assert!(Hops::new(1, Some(0), None).is_err());
+        assert!(Hops::new(3, None, Some(MAX_HOPS + 1)).is_err());
+        assert!(Hops::new(3, Some(1), Some(MAX_HOPS)).is_ok());
+    }
+
+    #[test]
+    fn hops_forbid_out_of_range() {
+        let hops = Hops::new(3, Some(2), Some(5)).unwrap();
+
+        for requested in [2, 3, 5] {
+            assert!(hops.check(Some(requested)).is_ok());
+        }
+
+        for requested in [0, 1, 6] {
+            let e = hops.check(Some(requested)).unwrap_err();
+
+            assert!(matches!(
+                e.downcast_ref::<RequestError>(),
+                Some(RequestError::Forbidden(_))
+            ));
+        }
     }
 }
@@ -0,0 +1,375 @@
+use crate::gateway::NodeUnselected;
+use crate::selection::{self, Strategy};
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// An unreachable guard is skipped for a while before it's tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+// A guard missing from the node pool for this long is replaced.
+const UNLISTED_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+// When guards are down, new ones are added up to this many times `count`.
+const MAX_GUARDS_FACTOR: usize = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Guard {
+    addr: SocketAddr,
+    fingerprint: String,
+    added_at: u64,
+    expires_at: u64,
+    #[serde(default)]
+    unreachable_at: Option<u64>,
+    #[serde(default)]
+    unlisted_since: Option<u64>,
+}
+
+impl Guard {
+    fn is_reachable(&self, now: u64) -> bool {
+        match self.unreachable_at {
+            Some(unreachable_at) => now >= unreachable_at + RETRY_AFTER.as_secs(),
+            None => true,
+        }
+    }
+
+    fn is_usable(&self, node_pool: &[NodeUnselected], now: u64) -> bool {
+        self.unlisted_since.is_none()
+            && self.is_reachable(now)
+            && node_pool.iter().any(|n| {
+                n.addr == self.addr && n.fingerprint == self.fingerprint && n.role.is_relay()
+            })
+    }
+}
+
+// A small set of entry nodes used as the first hop of every circuit, so a client is only
+// exposed to a few entries rather than eventually to every node.
+// Guards live until they expire, persisted in `path` across restarts.
+pub struct Guards {
+    path: Option<PathBuf>,
+    count: usize,
+    rotation: Duration,
+    guards: Mutex<Vec<Guard>>,
+}
+
+impl Guards {
+    pub fn load(path: Option<PathBuf>, count: usize, rotation: Duration) -> Result<Self> {
+        let guards = match &path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => Vec::new(),
+        };
+
+        Ok(Guards {
+            path,
+            count,
+            rotation,
+            guards: Mutex::new(guards),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.count > 0
+    }
+
+    // Drops expired and vanished guards and fills the set up to `count` usable guards.
+    pub fn refresh(&self, node_pool: &[NodeUnselected], strategy: &dyn Strategy) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        let now = unix_now();
+        let mut guards = self.guards.lock().unwrap();
+        let before = guards.clone();
+
+        for guard in guards.iter_mut() {
+            let listed = node_pool.iter().find(|n| n.addr == guard.addr);
+
+            guard.unlisted_since = match listed {
+                Some(_) => None,
+                None => guard.unlisted_since.or(Some(now)),
+            };
+
+            // another identity on the same address is another node
+            if matches!(listed, Some(n) if n.fingerprint != guard.fingerprint) {
+                guard.expires_at = now;
+            }
+        }
+
+        guards.retain(|g| {
+            let expired = now >= g.expires_at;
+            let vanished =
+                matches!(g.unlisted_since, Some(t) if now >= t + UNLISTED_GRACE.as_secs());
+
+            if expired || vanished {
+                info!("guard {} is rotated out", g.addr);
+            }
+
+            !expired && !vanished
+        });
+
+        self.fill(&mut guards, node_pool, strategy, &[], now)?;
+
+        if guards.len() != before.len()
+            || guards
+                .iter()
+                .zip(before.iter())
+                .any(|(a, b)| a.addr != b.addr)
+        {
+            self.save(&guards)?;
+        }
+
+        Ok(())
+    }
+
+    // The first usable guard not in `excluded`. Falls back to new guards when every guard is down.
+    pub fn pick(
+        &self,
+        node_pool: &[NodeUnselected],
+        strategy: &dyn Strategy,
+        excluded: &[SocketAddr],
+    ) -> Result<SocketAddr> {
+        let now = unix_now();
+        let mut guards = self.guards.lock().unwrap();
+
+        let usable = |guards: &[Guard]| {
+            guards
+                .iter()
+                .find(|g| !excluded.contains(&g.addr) && g.is_usable(node_pool, now))
+                .map(|g| g.addr)
+        };
+
+        if let Some(addr) = usable(&guards) {
+            return Ok(addr);
+        }
+
+        if self.fill(&mut guards, node_pool, strategy, excluded, now)? {
+            self.save(&guards)?;
+        }
+
+        match usable(&guards) {
+            Some(addr) => Ok(addr),
+            None => bail!(
+                "no guard is usable ({} guards, max {}). waiting for them to come back.",
+                guards.len(),
+                self.count * MAX_GUARDS_FACTOR
+            ),
+        }
+    }
+
+    pub fn mark_unreachable(&self, addr: &SocketAddr) {
+        let mut guards = self.guards.lock().unwrap();
+
+        if let Some(guard) = guards.iter_mut().find(|g| g.addr == *addr) {
+            warn!("guard {} is unreachable", addr);
+            guard.unreachable_at = Some(unix_now());
+        }
+    }
+
+    // Returns whether any guard was added.
+    fn fill(
+        &self,
+        guards: &mut Vec<Guard>,
+        node_pool: &[NodeUnselected],
+        strategy: &dyn Strategy,
+        excluded: &[SocketAddr],
+        now: u64,
+    ) -> Result<bool> {
+        let mut rng = rand::thread_rng();
+        let mut added = false;
+
+        while guards
+            .iter()
+            .filter(|g| !excluded.contains(&g.addr) && g.is_usable(node_pool, now))
+            .count()
+            < self.count
+            && guards.len() < self.count * MAX_GUARDS_FACTOR
+        {
+            let candidates: Vec<&NodeUnselected> = node_pool
+                .iter()
+                .filter(|n| {
+                    n.role.is_relay()
+                        && !excluded.contains(&n.addr)
+                        && !guards.iter().any(|g| g.addr == n.addr)
+                })
+                .collect();
+
+            let n = match selection::choose(strategy, &candidates, 1)?.first() {
+                Some(n) => *n,
+                None => break,
+            };
+
+            // spread rotations, so guards don't all change at once
+            let lifetime = rng.gen_range(self.rotation.as_secs()..=self.rotation.as_secs() * 3 / 2);
+
+            info!("guard {} is added", n.addr);
+
+            guards.push(Guard {
+                addr: n.addr,
+                fingerprint: n.fingerprint.clone(),
+                added_at: now,
+                expires_at: now + lifetime,
+                unreachable_at: None,
+                unlisted_since: None,
+            });
+            added = true;
+        }
+
+        Ok(added)
+    }
+
+    fn save(&self, guards: &[Guard]) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let tmp = path.with_extension("tmp");
+
+        std::fs::write(&tmp, serde_json::to_vec_pretty(guards)?)?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::Uniform;
+
+    const ROTATION: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+    fn node_pool(count: usize) -> Vec<NodeUnselected> {
+        (0..count)
+            .map(|i| NodeUnselected::for_test(&format!("10.{}.0.1:3000", i)))
+            .collect()
+    }
+
+    fn addrs(guards: &Guards) -> Vec<SocketAddr> {
+        guards
+            .guards
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|g| g.addr)
+            .collect()
+    }
+
+    #[test]
+    fn guards_fill_up_to_count() {
+        let node_pool = node_pool(6);
+        let guards = Guards::load(None, 2, ROTATION).unwrap();
+
+        guards.refresh(&node_pool, &Uniform).unwrap();
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        assert_eq!(addrs(&guards).len(), 2);
+
+        for g in guards.guards.lock().unwrap().iter() {
+            assert!(g.expires_at >= g.added_at + ROTATION.as_secs());
+            assert!(g.expires_at <= g.added_at + ROTATION.as_secs() * 3 / 2);
+        }
+    }
+
+    #[test]
+    fn guards_fall_back_up_to_max() {
+        let node_pool = node_pool(6);
+        let guards = Guards::load(None, 2, ROTATION).unwrap();
+
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        let first = addrs(&guards);
+
+        for addr in &first {
+            guards.mark_unreachable(addr);
+        }
+
+        let fallback = guards.pick(&node_pool, &Uniform, &[]).unwrap();
+
+        assert!(!first.contains(&fallback));
+        assert_eq!(addrs(&guards).len(), 2 * MAX_GUARDS_FACTOR);
+
+        for addr in addrs(&guards) {
+            guards.mark_unreachable(&addr);
+        }
+
+        assert!(guards.pick(&node_pool, &Uniform, &[]).is_err());
+        assert_eq!(addrs(&guards).len(), 2 * MAX_GUARDS_FACTOR);
+    }
+
+    #[test]
+    fn guards_retry_unreachable() {
+        let node_pool = node_pool(6);
+        let guards = Guards::load(None, 2, ROTATION).unwrap();
+
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        let addrs = addrs(&guards);
+
+        assert_eq!(guards.pick(&node_pool, &Uniform, &[]).unwrap(), addrs[0]);
+
+        guards.mark_unreachable(&addrs[0]);
+
+        assert_eq!(guards.pick(&node_pool, &Uniform, &[]).unwrap(), addrs[1]);
+
+        guards.guards.lock().unwrap()[0].unreachable_at = Some(unix_now() - RETRY_AFTER.as_secs());
+
+        assert_eq!(guards.pick(&node_pool, &Uniform, &[]).unwrap(), addrs[0]);
+    }
+
+    #[test]
+    fn guards_expire_on_new_fingerprint() {
+        let mut node_pool = node_pool(6);
+        let guards = Guards::load(None, 2, ROTATION).unwrap();
+
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        let replaced = guards.guards.lock().unwrap()[0].clone();
+        let n = node_pool
+            .iter_mut()
+            .find(|n| n.addr == replaced.addr)
+            .unwrap();
+        n.fingerprint = NodeUnselected::for_test("10.0.0.1:3000").fingerprint;
+
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        let refreshed = guards.guards.lock().unwrap();
+
+        // the new node on the same address may be picked again, but as a new guard
+        assert_eq!(refreshed.len(), 2);
+        assert!(!refreshed
+            .iter()
+            .any(|g| g.addr == replaced.addr && g.fingerprint == replaced.fingerprint));
+    }
+
+    #[test]
+    fn guards_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("guards.json");
+        let node_pool = node_pool(6);
+
+        let guards = Guards::load(Some(path.clone()), 3, ROTATION).unwrap();
+        guards.refresh(&node_pool, &Uniform).unwrap();
+
+        let loaded = Guards::load(Some(path), 3, ROTATION).unwrap();
+
+        assert_eq!(addrs(&loaded), addrs(&guards));
+        assert_eq!(
+            loaded.guards.lock().unwrap()[0].expires_at,
+            guards.guards.lock().unwrap()[0].expires_at
+        );
+
+        // the loaded guards are kept rather than picked again
+        loaded.refresh(&node_pool, &Uniform).unwrap();
+        assert_eq!(addrs(&loaded), addrs(&guards));
+    }
+}
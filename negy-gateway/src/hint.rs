@@ -0,0 +1,191 @@
+use crate::filter::NodeMatcher;
+use crate::http::bad_request;
+use anyhow::Result;
+
+const ENTRY_NODE_HEADER: &str = "negy-entry-node";
+const EXIT_NODE_HEADER: &str = "negy-exit-node";
+const HOPS_HEADER: &str = "negy-hops";
+
+// Per-request options of a client, sent in `Negy-*` headers or appended to the username
+// like `alice+exit=country:JP+hops=2`, for clients which can't set headers like SOCKS5 ones.
+#[derive(Debug, Clone, Default)]
+pub struct Hints {
+    // only used with several hops, as the only hop is the exit
+    pub entry_node: Option<NodeMatcher>,
+    pub exit_node: Option<NodeMatcher>,
+    // checked against --min-hops and --max-hops by the gateway
+    pub hops: Option<usize>,
+}
+
+impl Hints {
+    pub fn pins_node(&self) -> bool {
+        self.entry_node.is_some() || self.exit_node.is_some()
+    }
+
+    // Splits `username+key=value+...` into the username and its hints.
+    // Only known keys are hints, so `+` is still fine within usernames.
+    pub fn split_username(username: &str) -> Result<(&str, Hints)> {
+        let mut hints = Hints::default();
+        let mut username = username;
+
+        while let Some((rest, hint)) = username.rsplit_once('+') {
+            let known = match hint.split_once('=') {
+                Some((key, value)) => hints.set(key, value)?,
+                None => false,
+            };
+
+            if !known {
+                break;
+            }
+
+            username = rest;
+        }
+
+        Ok((username, hints))
+    }
+
+    // Headers take precedence over the hints of the username.
+    pub fn merge_headers(&mut self, headers: &[httparse::Header]) -> Result<()> {
+        for h in headers.iter() {
+            let key = if h.name.eq_ignore_ascii_case(ENTRY_NODE_HEADER) {
+                "entry"
+            } else if h.name.eq_ignore_ascii_case(EXIT_NODE_HEADER) {
+                "exit"
+            } else if h.name.eq_ignore_ascii_case(HOPS_HEADER) {
+                "hops"
+            } else {
+                continue;
+            };
+
+            let value = std::str::from_utf8(h.value).map_err(bad_request)?;
+
+            self.set(key, value.trim())?;
+        }
+
+        Ok(())
+    }
+
+    // Returns whether the key is known.
+    fn set(&mut self, key: &str, value: &str) -> Result<bool> {
+        match key {
+            "entry" => self.entry_node = Some(value.parse().map_err(bad_request)?),
+            "exit" => self.exit_node = Some(value.parse().map_err(bad_request)?),
+            "hops" => self.hops = Some(value.parse().map_err(bad_request)?),
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::RequestError;
+
+    fn is_bad_request(e: anyhow::Error) -> bool {
+        matches!(
+            e.downcast_ref::<RequestError>(),
+            Some(RequestError::BadRequest(_))
+        )
+    }
+
+    #[test]
+    fn split_username_hints() {
+        let (username, hints) = Hints::split_username("alice+exit=country:JP+hops=2").unwrap();
+
+        assert_eq!(username, "alice");
+        assert_eq!(hints.exit_node, Some(NodeMatcher::Country("JP".to_owned())));
+        assert_eq!(hints.hops, Some(2));
+        assert!(hints.entry_node.is_none());
+
+        let (username, hints) = Hints::split_username("alice+entry=203.0.113.1:3000").unwrap();
+
+        assert_eq!(username, "alice");
+        assert_eq!(
+            hints.entry_node,
+            Some(NodeMatcher::Addr("203.0.113.1:3000".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn split_username_with_plus() {
+        let (username, hints) = Hints::split_username("bob+smith").unwrap();
+
+        assert_eq!(username, "bob+smith");
+        assert!(!hints.pins_node() && hints.hops.is_none());
+
+        let (username, hints) = Hints::split_username("bob+smith+hops=4").unwrap();
+
+        assert_eq!(username, "bob+smith");
+        assert_eq!(hints.hops, Some(4));
+
+        // hints only count at the end
+        let (username, hints) = Hints::split_username("bob+hops=4+smith").unwrap();
+
+        assert_eq!(username, "bob+hops=4+smith");
+        assert!(hints.hops.is_none());
+
+        let (username, _) = Hints::split_username("bob+unknown=1").unwrap();
+
+        assert_eq!(username, "bob+unknown=1");
+    }
+
+    #[test]
+    fn merge_headers_override_username() {
+        let (_, mut hints) = Hints::split_username("alice+exit=country:JP+hops=2").unwrap();
+
+        hints
+            .merge_headers(&[
+                httparse::Header {
+                    name: "Negy-Exit-Node",
+                    value: b"country:US",
+                },
+                httparse::Header {
+                    name: "negy-entry-node",
+                    value: b" 10.0.0.0/8 ",
+                },
+                httparse::Header {
+                    name: "Host",
+                    value: b"example.com",
+                },
+            ])
+            .unwrap();
+
+        assert_eq!(hints.exit_node, Some(NodeMatcher::Country("US".to_owned())));
+        assert_eq!(
+            hints.entry_node,
+            Some("10.0.0.0/8".parse::<NodeMatcher>().unwrap())
+        );
+        assert_eq!(hints.hops, Some(2));
+    }
+
+    #[test]
+    fn reject_invalid_hints() {
+        assert!(is_bad_request(
+            Hints::split_username("alice+hops=two").unwrap_err()
+        ));
+        assert!(is_bad_request(
+            Hints::split_username("alice+exit=country:JPN").unwrap_err()
+        ));
+        assert!(is_bad_request(
+            Hints::split_username("alice+entry=").unwrap_err()
+        ));
+        assert!(is_bad_request(
+            Hints::default()
+                .merge_headers(&[httparse::Header {
+                    name: "Negy-Hops",
+                    value: b"-1",
+                }])
+                .unwrap_err()
+        ));
+        assert!(is_bad_request(
+            Hints::default()
+                .merge_headers(&[httparse::Header {
+                    name: "Negy-Exit-Node",
+                    value: b"\xff",
+                }])
+                .unwrap_err()
+        ));
+    }
+}
@@ -1,16 +1,42 @@
 #[macro_use]
 extern crate log;
 
+mod auth;
+mod circuit;
+mod filter;
 mod gateway;
+mod guard;
+mod hint;
+mod http;
+mod quota;
+mod selection;
+mod socks5;
 
-use crate::gateway::{Gateway, NodeUnselected};
-use anyhow::Result;
-use clap::Parser;
+use crate::auth::{hash_password, Auth, Credentials};
+use crate::circuit::CircuitPool;
+use crate::filter::{parse_matchers, NodeFilter};
+use crate::gateway::{Frontend, Gateway, Hops, NodeUnselected};
+use crate::guard::Guards;
+use crate::quota::Quotas;
+use crate::selection::{PathSelection, StrategyKind};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use negy_common::key_exchange;
+use negy_common::policy::Policy;
+use negy_common::protocol::PROTOCOL_VERSION;
 use negy_node_pool::req::ListNodeResponse;
 use openssl::rsa::Rsa;
 use semver::Version;
+use std::io::BufRead;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use warp::Filter;
+
+// Evaluated after --policy rules.
+const DEFAULT_POLICY: [&str; 2] = ["reject private", "reject *:25"];
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -23,33 +49,128 @@ struct Args {
     node_pool_endpoint: String,
     #[clap(short, long, value_parser, default_value = "3")]
     hops: usize,
+    /// Fewest hops clients may ask for with a `Negy-Hops` header or by appending
+    /// `+hops=<count>` to their username. Defaults to --hops.
+    #[clap(long, value_parser)]
+    min_hops: Option<usize>,
+    /// Most hops clients may ask for. Defaults to --hops.
+    #[clap(long, value_parser)]
+    max_hops: Option<usize>,
     #[clap(short, long, value_parser)]
     auth_token: Option<String>,
+    /// JSON file of users, reloaded when it changes
+    #[clap(long, value_parser)]
+    credentials_file: Option<PathBuf>,
+    #[clap(long, value_parser, default_value = "negy")]
+    auth_realm: String,
     #[clap(short, long, value_parser)]
     min_version: Option<String>,
+    /// Only uses nodes matching one of these, like `name:AMAZON-02`, `country:JP`, `203.0.113.0/24`,
+    /// `203.0.113.1:3000` or `key:<fingerprint>`. Can be repeated or comma separated.
+    #[clap(long, value_parser)]
+    include_nodes: Vec<String>,
+    /// Never uses nodes matching one of these. Takes precedence over --include-nodes.
+    #[clap(long, value_parser)]
+    exclude_nodes: Vec<String>,
+    /// Comma separated RDAP names to exclude, same as --exclude-nodes name:<name>
     #[clap(long, value_parser)]
     block_network: Option<String>,
+    #[clap(long, value_parser, default_value = "2")]
+    circuit_retries: usize,
+    #[clap(long, value_parser, default_value = "4")]
+    circuit_pool_size: usize,
+    /// Seconds until a pooled circuit is no longer handed out
+    #[clap(long, value_parser, default_value = "300")]
+    circuit_max_age: u64,
+    /// Keeps the entry guards across restarts. Without it, they're picked again on every start.
+    #[clap(long, value_parser)]
+    guards_file: Option<PathBuf>,
+    /// Number of entry guards. 0 picks the first hop at random for every circuit
+    #[clap(long, value_parser, default_value = "3")]
+    guard_count: usize,
+    /// Days until a guard is replaced, plus up to half as long again
+    #[clap(long, value_parser, default_value = "60")]
+    guard_rotation: u64,
+    /// `weighted` favors nodes with more bandwidth and lower RTT, `uniform` ignores both
+    #[clap(long, value_parser, default_value = "weighted")]
+    path_selection: StrategyKind,
+    /// First hops are picked among nodes matching these instead of the guards. Takes the same
+    /// forms as --include-nodes. Clients can pin one per request with a `Negy-Entry-Node`
+    /// header or by appending `+entry=<node>` to their username.
+    #[clap(long, value_parser)]
+    entry_nodes: Vec<String>,
+    /// Last hops are picked among nodes matching these. Clients can pin one per request
+    /// with a `Negy-Exit-Node` header or by appending `+exit=<node>` to their username.
+    #[clap(long, value_parser)]
+    exit_nodes: Vec<String>,
+    /// Lets nodes of the same /16 (IPv4) or /32 (IPv6) share a circuit, like in a local network
+    #[clap(long, value_parser)]
+    allow_same_subnet: bool,
+    /// Listener which only speaks SOCKS5. The main port detects it on its own.
+    #[clap(long, value_parser)]
+    socks5_port: Option<u16>,
+    /// Destination rule like `reject *.example.com` or `accept 10.0.0.0/8:443`. Can be repeated.
+    #[clap(long, value_parser)]
+    policy: Vec<String>,
+    /// Serves usage counters of users at /usage. Don't expose it publicly.
+    #[clap(long, value_parser)]
+    admin_addr: Option<SocketAddr>,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-async fn spawn_inner(
-    client: TcpStream,
-    node_pool: Arc<RwLock<Vec<NodeUnselected>>>,
-    hops: usize,
-    auth_token: Option<String>,
-) -> Result<()> {
-    Gateway::new(client, auth_token)
-        .fetch_nodes(node_pool.clone(), hops)?
-        .handshake()
-        .await?
-        .tunnel()
-        .await?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Read a password from stdin and print its hash for --credentials-file
+    HashPassword,
+}
+
+// Shared by every client connection.
+#[derive(Clone)]
+struct Shared {
+    listed_nodes: Arc<RwLock<Vec<NodeUnselected>>>,
+    selection: Arc<PathSelection>,
+    circuit_pool: Arc<CircuitPool>,
+    auth: Arc<Auth>,
+    quotas: Arc<Quotas>,
+    policy: Arc<Policy>,
+    hops: Hops,
+    circuit_retries: usize,
+}
+
+async fn spawn_inner(client: TcpStream, frontend: Frontend, shared: Shared) -> Result<()> {
+    Gateway::new(
+        client,
+        shared.auth,
+        shared.quotas,
+        shared.policy,
+        shared.hops,
+        frontend,
+    )
+    .accept()
+    .await?
+    .fetch_nodes(
+        shared.listed_nodes,
+        shared.selection,
+        &shared.circuit_pool,
+        shared.hops.default,
+    )?
+    .handshake(shared.circuit_retries)
+    .await?
+    .tunnel()
+    .await?;
 
     Ok(())
 }
+
+async fn usage(quotas: Arc<Quotas>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&quotas.report()))
+}
+
 async fn fetch_nodes_unselected(
     node_pool_endpoint: &str,
     min_version: &Option<String>,
-    block_network: &Option<String>,
+    node_filter: &NodeFilter,
 ) -> Result<Vec<NodeUnselected>> {
     let res = reqwest::Client::new()
         .get(format!("{}/list", node_pool_endpoint))
@@ -60,11 +181,64 @@ async fn fetch_nodes_unselected(
     let nodes_unselected: Vec<NodeUnselected> = res
         .nodes
         .into_iter()
-        .map(|n| NodeUnselected {
-            addr: n.addr,
-            rsa: Rsa::public_key_from_pem(&base64::decode(&n.public_key).unwrap()).unwrap(),
-            name: n.name,
-            version: n.version,
+        .filter(|n| {
+            if n.protocol_version != Some(PROTOCOL_VERSION) {
+                debug!(
+                    "skip node {} (protocol version {:?}, expected {})",
+                    n.addr, n.protocol_version, PROTOCOL_VERSION
+                );
+                return false;
+            }
+
+            true
+        })
+        .filter_map(|n| {
+            let exit_policy = match n.exit_policy.as_deref().map(Policy::parse) {
+                Some(Ok(exit_policy)) => Some(exit_policy),
+                Some(Err(e)) => {
+                    debug!("skip node {} (invalid exit policy {:?})", n.addr, e);
+                    return None;
+                }
+                None => None,
+            };
+
+            let rsa = match base64::decode(&n.public_key)
+                .map_err(anyhow::Error::from)
+                .and_then(|pem| Ok(Rsa::public_key_from_pem(&pem)?))
+            {
+                Ok(rsa) => rsa,
+                Err(e) => {
+                    debug!("skip node {} (invalid public key {:?})", n.addr, e);
+                    return None;
+                }
+            };
+
+            let fingerprint = match key_exchange::fingerprint(&rsa) {
+                Ok(fingerprint) => fingerprint,
+                Err(e) => {
+                    debug!("skip node {} (invalid fingerprint {:?})", n.addr, e);
+                    return None;
+                }
+            };
+
+            Some(NodeUnselected {
+                addr: n.addr,
+                rsa,
+                fingerprint,
+                name: n.name,
+                country: n.country,
+                version: n.version,
+                role: n.role.unwrap_or_default(),
+                bandwidth: n.bandwidth,
+                rtt_ms: n.rtt_ms,
+                family: n
+                    .family
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|f| f.to_lowercase())
+                    .collect(),
+                exit_policy,
+            })
         })
         .filter(|n| {
             if let Some(min_version) = &min_version {
@@ -74,14 +248,12 @@ async fn fetch_nodes_unselected(
             }
         })
         .filter(|n| {
-            if let (Some(name), Some(block_network)) = (&n.name, &block_network) {
-                block_network
-                    .split(",")
-                    .collect::<Vec<_>>()
-                    .contains(&name.as_str())
-            } else {
-                true
+            if !node_filter.allows(n) {
+                debug!("skip node {} (filtered out)", n.addr);
+                return false;
             }
+
+            true
         })
         .collect();
 
@@ -90,21 +262,105 @@ async fn fetch_nodes_unselected(
 
 async fn spawn(
     listener: TcpListener,
-    node_pool_endpoint: String,
-    hops: usize,
-    auth_token: Option<String>,
-    min_version: Option<String>,
-    block_network: Option<String>,
+    socks5_listener: Option<TcpListener>,
+    args: Args,
 ) -> Result<()> {
     let listed_nodes: Arc<RwLock<Vec<NodeUnselected>>> = Arc::new(RwLock::new(Vec::new()));
     let listed_nodes_fetch = listed_nodes.clone();
-    let listed_nodes_accept = listed_nodes.clone();
+    let circuit_pool = Arc::new(CircuitPool::new(
+        args.circuit_pool_size,
+        Duration::from_secs(args.circuit_max_age),
+    ));
+    let circuit_pool_maintain = circuit_pool.clone();
+    let listed_nodes_maintain = listed_nodes.clone();
+    let hops = Hops::new(args.hops, args.min_hops, args.max_hops)?;
+    let circuit_retries = args.circuit_retries;
+    let node_pool_endpoint = args.node_pool_endpoint;
+    let min_version = args.min_version;
+    let block_network: Vec<String> = match &args.block_network {
+        Some(block_network) => block_network
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| format!("name:{}", name.trim()))
+            .collect(),
+        None => Vec::new(),
+    };
+    let node_filter = NodeFilter::parse(
+        &args.include_nodes,
+        &[&args.exclude_nodes[..], &block_network[..]].concat(),
+    )?;
+
+    for m in node_filter.include() {
+        info!("include nodes: {}", m);
+    }
+
+    for m in node_filter.exclude() {
+        info!("exclude nodes: {}", m);
+    }
+
+    let selection = Arc::new(PathSelection {
+        strategy: args.path_selection.build(),
+        guards: Guards::load(
+            args.guards_file,
+            args.guard_count,
+            Duration::from_secs(args.guard_rotation * 24 * 60 * 60),
+        )?,
+        distinct_subnets: !args.allow_same_subnet,
+        entry_nodes: parse_matchers(&args.entry_nodes)?,
+        exit_nodes: parse_matchers(&args.exit_nodes)?,
+    });
+
+    for m in &selection.entry_nodes {
+        info!("entry nodes: {}", m);
+    }
+
+    for m in &selection.exit_nodes {
+        info!("exit nodes: {}", m);
+    }
+
+    let selection_fetch = selection.clone();
+    let selection_maintain = selection.clone();
+
+    if args.auth_realm.contains(&['"', '\\'][..]) {
+        bail!("--auth-realm must not contain quotes or backslashes")
+    }
+
+    let credentials = match &args.credentials_file {
+        Some(credentials_file) => Some(Credentials::load(credentials_file)?),
+        None => None,
+    };
+
+    let auth = Arc::new(Auth {
+        token: args.auth_token,
+        credentials,
+        realm: args.auth_realm,
+    });
+
+    if auth.credentials.is_some() {
+        let auth = auth.clone();
+
+        tokio::spawn(async move {
+            if let Some(credentials) = &auth.credentials {
+                if let Err(e) = credentials.watch().await {
+                    error!("{:?}", e);
+                }
+            }
+        });
+    }
 
     tokio::spawn(async move {
         loop {
-            match fetch_nodes_unselected(&node_pool_endpoint, &min_version, &block_network).await {
+            match fetch_nodes_unselected(&node_pool_endpoint, &min_version, &node_filter).await {
                 Ok(nodes_unselected) => {
                     info!("fetched {} nodes", nodes_unselected.len());
+
+                    if let Err(e) = selection_fetch
+                        .guards
+                        .refresh(&nodes_unselected, selection_fetch.strategy.as_ref())
+                    {
+                        warn!("failed to refresh guards {:?}", e);
+                    }
+
                     *listed_nodes_fetch.write().unwrap() = nodes_unselected;
                 }
                 Err(e) => {
@@ -114,17 +370,74 @@ async fn spawn(
                 }
             }
 
-            tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+            tokio::time::sleep(Duration::from_secs(30)).await;
         }
     });
 
+    if args.circuit_pool_size > 0 {
+        tokio::spawn(async move {
+            if let Err(e) = circuit_pool_maintain
+                .maintain(
+                    listed_nodes_maintain,
+                    selection_maintain,
+                    hops.default,
+                    circuit_retries,
+                )
+                .await
+            {
+                error!("{:?}", e);
+            }
+        });
+    }
+
+    let quotas = Arc::new(Quotas::new());
+    let policy = Arc::new(Policy::parse(
+        &[&args.policy[..], &DEFAULT_POLICY.map(String::from)[..]].concat(),
+    )?);
+
+    for rule in policy.rules() {
+        info!("destination policy: {}", rule);
+    }
+
+    if let Some(admin_addr) = args.admin_addr {
+        let quotas = quotas.clone();
+        let quotas_filter = warp::any().map(move || quotas.clone());
+
+        let usage = warp::path!("usage")
+            .and(warp::filters::method::get())
+            .and(quotas_filter)
+            .and_then(usage);
+
+        info!("start admin endpoint on {}", admin_addr);
+
+        tokio::spawn(warp::serve(usage).run(admin_addr));
+    }
+
+    let shared = Shared {
+        listed_nodes,
+        selection,
+        circuit_pool,
+        auth,
+        quotas,
+        policy,
+        hops,
+        circuit_retries,
+    };
+
+    if let Some(socks5_listener) = socks5_listener {
+        tokio::spawn(serve(socks5_listener, Frontend::Socks5, shared.clone()));
+    }
+
+    serve(listener, Frontend::Auto, shared).await
+}
+
+async fn serve(listener: TcpListener, frontend: Frontend, shared: Shared) -> Result<()> {
     loop {
         let (client, _) = listener.accept().await?;
-        let listed_nodes = listed_nodes_accept.clone();
-        let auth_token_cloned = auth_token.clone();
+        let shared = shared.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = spawn_inner(client, listed_nodes, hops, auth_token_cloned).await {
+            if let Err(e) = spawn_inner(client, frontend, shared).await {
                 error!("{:?}", e);
             }
         });
@@ -133,7 +446,7 @@ async fn spawn(
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
 
@@ -145,21 +458,37 @@ async fn main() -> Result<()> {
     })?;
 
     let args = Args::parse();
+
+    if let Some(Command::HashPassword) = args.command {
+        let mut password = String::new();
+        std::io::stdin().lock().read_line(&mut password)?;
+
+        println!(
+            "{}",
+            hash_password(password.trim_end_matches(&['\r', '\n'][..]))?
+        );
+
+        return Ok(());
+    }
+
     let bind_addr = format!("{}:{}", args.bind, args.port);
 
     info!("start listening on {}", bind_addr);
 
     let listener = TcpListener::bind(bind_addr).await?;
 
-    spawn(
-        listener,
-        args.node_pool_endpoint,
-        args.hops,
-        args.auth_token,
-        args.min_version,
-        args.block_network,
-    )
-    .await?;
+    let socks5_listener = match args.socks5_port {
+        Some(socks5_port) => {
+            let socks5_bind_addr = format!("{}:{}", args.bind, socks5_port);
+
+            info!("start listening on {} (SOCKS5)", socks5_bind_addr);
+
+            Some(TcpListener::bind(socks5_bind_addr).await?)
+        }
+        None => None,
+    };
+
+    spawn(listener, socks5_listener, args).await?;
 
     Ok(())
 }
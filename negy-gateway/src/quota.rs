@@ -0,0 +1,415 @@
+use crate::auth::User;
+use crate::http::too_many_requests;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Usage of every user authenticated against the credentials file, kept across reloads.
+#[derive(Default)]
+pub struct Quotas {
+    usages: Mutex<HashMap<String, Arc<Mutex<Usage>>>>,
+}
+
+struct Usage {
+    connections: usize,
+    requests: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    period_bytes: u64,
+    period_started_at: Instant,
+    period_started_at_unix: u64,
+    rate_limiter: RateLimiter,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub username: String,
+    pub connections: usize,
+    pub requests: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub period_bytes: u64,
+    pub period_started_at: u64,
+}
+
+impl Quotas {
+    pub fn new() -> Self {
+        Quotas::default()
+    }
+
+    // Counts a new connection of the user, or fails when one of its limits is reached.
+    pub fn open(&self, user: &User) -> Result<Session> {
+        let usage = self
+            .usages
+            .lock()
+            .unwrap()
+            .entry(user.username.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(Usage::new())))
+            .clone();
+
+        {
+            let mut u = usage.lock().unwrap();
+
+            if let Some(max_connections) = user.max_connections {
+                if u.connections >= max_connections {
+                    return Err(too_many_requests(format!(
+                        "user {} reached {} concurrent connections",
+                        user.username, max_connections
+                    )));
+                }
+            }
+
+            u.renew_period(user.period);
+
+            if let Some(bytes_per_period) = user.bytes_per_period {
+                if u.period_bytes >= bytes_per_period {
+                    return Err(too_many_requests(format!(
+                        "user {} used up {} bytes of this period",
+                        user.username, bytes_per_period
+                    )));
+                }
+            }
+
+            // taken last, so connections rejected by the other limits don't use up the rate
+            if let Some(requests_per_second) = user.requests_per_second {
+                if !u.rate_limiter.acquire(requests_per_second) {
+                    return Err(too_many_requests(format!(
+                        "user {} exceeded {} requests per second",
+                        user.username, requests_per_second
+                    )));
+                }
+            }
+
+            u.connections += 1;
+            u.requests += 1;
+        }
+
+        Ok(Session {
+            user: user.clone(),
+            usage,
+        })
+    }
+
+    pub fn report(&self) -> Vec<UsageReport> {
+        let mut reports: Vec<UsageReport> = self
+            .usages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(username, usage)| {
+                let u = usage.lock().unwrap();
+
+                UsageReport {
+                    username: username.clone(),
+                    connections: u.connections,
+                    requests: u.requests,
+                    bytes_sent: u.bytes_sent,
+                    bytes_received: u.bytes_received,
+                    period_bytes: u.period_bytes,
+                    period_started_at: u.period_started_at_unix,
+                }
+            })
+            .collect();
+
+        reports.sort_by(|a, b| a.username.cmp(&b.username));
+        reports
+    }
+}
+
+impl Usage {
+    fn new() -> Self {
+        Usage {
+            connections: 0,
+            requests: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            period_bytes: 0,
+            period_started_at: Instant::now(),
+            period_started_at_unix: unix_now(),
+            rate_limiter: RateLimiter::new(),
+        }
+    }
+
+    fn renew_period(&mut self, period: u64) {
+        if self.period_started_at.elapsed() >= Duration::from_secs(period) {
+            self.period_bytes = 0;
+            self.period_started_at = Instant::now();
+            self.period_started_at_unix = unix_now();
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Token bucket allowing bursts of one second worth of requests.
+struct RateLimiter {
+    tokens: Option<f64>,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        RateLimiter {
+            tokens: None,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn acquire(&mut self, requests_per_second: u32) -> bool {
+        let capacity = requests_per_second as f64;
+        let elapsed = self.updated_at.elapsed().as_secs_f64();
+        let tokens = match self.tokens {
+            Some(tokens) => (tokens + elapsed * capacity).min(capacity),
+            None => capacity,
+        };
+
+        self.updated_at = Instant::now();
+
+        if tokens < 1.0 {
+            self.tokens = Some(tokens);
+            return false;
+        }
+
+        self.tokens = Some(tokens - 1.0);
+
+        true
+    }
+}
+
+// One connection of a user. The connection is released on drop.
+pub struct Session {
+    pub user: User,
+    usage: Arc<Mutex<Usage>>,
+}
+
+impl Session {
+    fn record(&self, sent: u64, received: u64) -> io::Result<()> {
+        let mut u = self.usage.lock().unwrap();
+
+        u.renew_period(self.user.period);
+        u.bytes_sent += sent;
+        u.bytes_received += received;
+        u.period_bytes += sent + received;
+
+        match self.user.bytes_per_period {
+            Some(bytes_per_period) if u.period_bytes > bytes_per_period => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!(
+                    "user {} used up {} bytes of this period",
+                    self.user.username, bytes_per_period
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn record_sent(&self, len: usize) -> io::Result<()> {
+        self.record(len as u64, 0)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.usage.lock().unwrap().connections -= 1;
+    }
+}
+
+// Counts the bytes of a client connection, and cuts it once the byte quota is used up.
+pub struct Metered<S> {
+    inner: S,
+    session: Session,
+}
+
+impl<S> Metered<S> {
+    pub fn new(inner: S, session: Session) -> Self {
+        Metered { inner, session }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                Poll::Ready(self.session.record_sent(buf.filled().len() - filled))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => Poll::Ready(self.session.record(0, n as u64).map(|_| n)),
+            poll => poll,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_test::io::Builder;
+
+    fn user(limits: serde_json::Value) -> User {
+        let mut user = serde_json::json!({"username": "alice", "password_hash": ""});
+        user.as_object_mut()
+            .unwrap()
+            .extend(limits.as_object().unwrap().clone());
+
+        serde_json::from_value(user).unwrap()
+    }
+
+    fn rewind(at: &mut Instant, duration: Duration) {
+        *at = Instant::now() - duration;
+    }
+
+    #[test]
+    fn rate_limiter_burst_and_refill() {
+        let mut limiter = RateLimiter::new();
+
+        for _ in 0..10 {
+            assert!(limiter.acquire(10));
+        }
+
+        assert!(!limiter.acquire(10));
+
+        rewind(&mut limiter.updated_at, Duration::from_millis(250));
+
+        assert!(limiter.acquire(10));
+        assert!(limiter.acquire(10));
+        assert!(!limiter.acquire(10));
+
+        // refills up to one second worth of requests
+        rewind(&mut limiter.updated_at, Duration::from_secs(60));
+
+        for _ in 0..10 {
+            assert!(limiter.acquire(10));
+        }
+
+        assert!(!limiter.acquire(10));
+    }
+
+    #[test]
+    fn quotas_max_connections() {
+        let quotas = Quotas::new();
+        let user = user(serde_json::json!({"max_connections": 2}));
+
+        let first = quotas.open(&user).unwrap();
+        let second = quotas.open(&user).unwrap();
+
+        assert!(quotas.open(&user).is_err());
+
+        drop(first);
+
+        let third = quotas.open(&user).unwrap();
+        assert_eq!(quotas.report()[0].connections, 2);
+
+        drop(second);
+        drop(third);
+
+        let report = &quotas.report()[0];
+        assert_eq!(report.connections, 0);
+        assert_eq!(report.requests, 3);
+    }
+
+    #[test]
+    fn quotas_requests_per_second() {
+        let quotas = Quotas::new();
+        let user = user(serde_json::json!({"requests_per_second": 2}));
+
+        assert!(quotas.open(&user).is_ok());
+        assert!(quotas.open(&user).is_ok());
+        assert!(quotas.open(&user).is_err());
+    }
+
+    #[test]
+    fn quotas_rejected_connections_keep_rate() {
+        let quotas = Quotas::new();
+        let user = user(serde_json::json!({"max_connections": 1, "requests_per_second": 2}));
+
+        let first = quotas.open(&user).unwrap();
+
+        for _ in 0..5 {
+            assert!(quotas.open(&user).is_err());
+        }
+
+        drop(first);
+
+        assert!(quotas.open(&user).is_ok());
+    }
+
+    #[test]
+    fn usage_renew_period() {
+        let mut usage = Usage::new();
+        usage.period_bytes = 100;
+
+        usage.renew_period(60);
+        assert_eq!(usage.period_bytes, 100);
+
+        rewind(&mut usage.period_started_at, Duration::from_secs(61));
+
+        usage.renew_period(60);
+        assert_eq!(usage.period_bytes, 0);
+        assert!(usage.period_started_at.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn metered_abort_over_quota() {
+        let quotas = Quotas::new();
+        let user = user(serde_json::json!({"bytes_per_period": 8}));
+
+        let inner = Builder::new()
+            .read(b"hello")
+            .write(b"abc")
+            .read(b"world")
+            .build();
+        let mut metered = Metered::new(inner, quotas.open(&user).unwrap());
+        let mut buf = [0; 5];
+
+        metered.read_exact(&mut buf).await.unwrap();
+        metered.write_all(b"abc").await.unwrap();
+
+        let e = metered.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+
+        let report = &quotas.report()[0];
+        assert_eq!(report.bytes_sent, 10);
+        assert_eq!(report.bytes_received, 3);
+
+        drop(metered);
+
+        // the period is used up until it renews
+        assert!(quotas.open(&user).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use crate::filter::NodeMatcher;
+use crate::gateway::NodeUnselected;
+use crate::guard::Guards;
+use anyhow::{bail, Result};
+use negy_common::cidr::{self, Cidr};
+use rand::seq::SliceRandom;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// Weights are kept within this factor of the median, so a node can't attract most circuits
+// by advertising a huge bandwidth, and slow nodes still carry some of them.
+const MAX_WEIGHT_FACTOR: f64 = 10.0;
+// RTT at which a node's weight is halved
+const RTT_HALVING_MS: f64 = 200.0;
+
+// How the nodes of a circuit are picked from the node pool.
+pub struct PathSelection {
+    pub strategy: Box<dyn Strategy>,
+    pub guards: Guards,
+    // no two hops in the same /16 (IPv4) or /32 (IPv6)
+    pub distinct_subnets: bool,
+    // the first hops are picked among these instead of the guards
+    pub entry_nodes: Vec<NodeMatcher>,
+    // the last hops are picked among these
+    pub exit_nodes: Vec<NodeMatcher>,
+}
+
+impl PathSelection {
+    // Whether the first hop is picked among a few nodes rather than every relay.
+    pub fn pins_entry(&self) -> bool {
+        !self.entry_nodes.is_empty() || self.guards.is_enabled()
+    }
+
+    pub fn allows_exit(&self, n: &NodeUnselected) -> bool {
+        self.exit_nodes.is_empty() || self.exit_nodes.iter().any(|m| m.matches(n))
+    }
+
+    // Nodes likely run by the same operator, which would see the whole circuit together.
+    // Families only count when both nodes declare each other, so nobody can push nodes off circuits.
+    pub fn are_related(&self, a: &NodeUnselected, b: &NodeUnselected) -> bool {
+        (self.distinct_subnets && same_subnet(&a.addr.ip(), &b.addr.ip()))
+            || matches!((&a.name, &b.name), (Some(x), Some(y)) if x == y)
+            || (a.family.contains(&b.fingerprint) && b.family.contains(&a.fingerprint))
+    }
+}
+
+#[cfg(test)]
+impl PathSelection {
+    // Uniform, without guards nor pinned nodes.
+    pub fn for_test(distinct_subnets: bool) -> Self {
+        PathSelection {
+            strategy: Box::new(Uniform),
+            guards: Guards::load(None, 0, std::time::Duration::ZERO).unwrap(),
+            distinct_subnets,
+            entry_nodes: Vec::new(),
+            exit_nodes: Vec::new(),
+        }
+    }
+}
+
+// How likely each candidate of a hop is picked. Selection stays random either way.
+pub trait Strategy: Send + Sync {
+    fn weights(&self, candidates: &[&NodeUnselected]) -> Vec<f64>;
+}
+
+pub struct Uniform;
+
+impl Strategy for Uniform {
+    fn weights(&self, candidates: &[&NodeUnselected]) -> Vec<f64> {
+        vec![1.0; candidates.len()]
+    }
+}
+
+// Advertised bandwidth, discounted by the RTT the node pool measured.
+// Nodes which advertise nothing are treated like the median node.
+pub struct Weighted;
+
+impl Strategy for Weighted {
+    fn weights(&self, candidates: &[&NodeUnselected]) -> Vec<f64> {
+        let median_bandwidth = median(
+            candidates
+                .iter()
+                .filter_map(|n| n.bandwidth)
+                .map(|b| b as f64),
+        )
+        .unwrap_or(1.0);
+        let median_rtt =
+            median(candidates.iter().filter_map(|n| n.rtt_ms).map(|r| r as f64)).unwrap_or(0.0);
+
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|n| {
+                let bandwidth = n.bandwidth.map(|b| b as f64).unwrap_or(median_bandwidth);
+                let rtt = n.rtt_ms.map(|r| r as f64).unwrap_or(median_rtt);
+
+                bandwidth.max(1.0) * RTT_HALVING_MS / (RTT_HALVING_MS + rtt)
+            })
+            .collect();
+
+        let median_weight = median(weights.iter().copied()).unwrap_or(1.0);
+
+        weights
+            .into_iter()
+            .map(|w| {
+                w.clamp(
+                    median_weight / MAX_WEIGHT_FACTOR,
+                    median_weight * MAX_WEIGHT_FACTOR,
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrategyKind {
+    Uniform,
+    Weighted,
+}
+
+impl StrategyKind {
+    pub fn build(&self) -> Box<dyn Strategy> {
+        match self {
+            StrategyKind::Uniform => Box::new(Uniform),
+            StrategyKind::Weighted => Box::new(Weighted),
+        }
+    }
+}
+
+impl FromStr for StrategyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(StrategyKind::Uniform),
+            "weighted" => Ok(StrategyKind::Weighted),
+            _ => Err(format!(
+                "unknown path selection {:?} (uniform or weighted)",
+                s
+            )),
+        }
+    }
+}
+
+// Picks up to `count` distinct candidates at random in proportion to their weights.
+pub fn choose<'a>(
+    strategy: &dyn Strategy,
+    candidates: &[&'a NodeUnselected],
+    count: usize,
+) -> Result<Vec<&'a NodeUnselected>> {
+    let weights = strategy.weights(candidates);
+
+    if weights.len() != candidates.len() || weights.iter().any(|w| !w.is_finite() || *w <= 0.0) {
+        bail!("path selection gave invalid weights {:?}", weights)
+    }
+
+    let weighted: Vec<(&NodeUnselected, f64)> = candidates.iter().copied().zip(weights).collect();
+
+    Ok(weighted
+        .choose_multiple_weighted(&mut rand::thread_rng(), count, |(_, w)| *w)?
+        .map(|(n, _)| *n)
+        .collect())
+}
+
+fn same_subnet(a: &IpAddr, b: &IpAddr) -> bool {
+    let a = cidr::canonical(a);
+    let prefix_len = if a.is_ipv4() { 16 } else { 32 };
+
+    match Cidr::new(a, prefix_len) {
+        Ok(subnet) => subnet.contains(b),
+        Err(_) => false,
+    }
+}
+
+fn median<I: Iterator<Item = f64>>(values: I) -> Option<f64> {
+    let mut values: Vec<f64> = values.collect();
+
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(addr: &str, bandwidth: Option<u64>, rtt_ms: Option<u64>) -> NodeUnselected {
+        NodeUnselected {
+            bandwidth,
+            rtt_ms,
+            ..NodeUnselected::for_test(addr)
+        }
+    }
+
+    fn weights(nodes: &[NodeUnselected]) -> Vec<f64> {
+        Weighted.weights(&nodes.iter().collect::<Vec<_>>())
+    }
+
+    struct Fixed(Vec<f64>);
+
+    impl Strategy for Fixed {
+        fn weights(&self, _: &[&NodeUnselected]) -> Vec<f64> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn related_same_subnet() {
+        let selection = PathSelection::for_test(true);
+        let related = |a: &str, b: &str| {
+            selection.are_related(&NodeUnselected::for_test(a), &NodeUnselected::for_test(b))
+        };
+
+        assert!(related("10.0.1.1:3000", "10.0.200.1:3001"));
+        assert!(!related("10.0.1.1:3000", "10.1.1.1:3000"));
+        assert!(related("[2001:db8:1::1]:3000", "[2001:db8:2::1]:3000"));
+        assert!(!related("[2001:db8:1::1]:3000", "[2001:db9:1::1]:3000"));
+        assert!(!related("10.0.1.1:3000", "[2001:db8:1::1]:3000"));
+
+        let selection = PathSelection::for_test(false);
+
+        assert!(!selection.are_related(
+            &NodeUnselected::for_test("10.0.1.1:3000"),
+            &NodeUnselected::for_test("10.0.1.2:3000")
+        ));
+    }
+
+    #[test]
+    fn related_same_network_name() {
+        let selection = PathSelection::for_test(true);
+        let named = |addr: &str, name: Option<&str>| NodeUnselected {
+            name: name.map(String::from),
+            ..NodeUnselected::for_test(addr)
+        };
+
+        assert!(selection.are_related(
+            &named("10.0.0.1:3000", Some("AMAZON-02")),
+            &named("10.1.0.1:3000", Some("AMAZON-02"))
+        ));
+        assert!(!selection.are_related(
+            &named("10.0.0.1:3000", Some("AMAZON-02")),
+            &named("10.1.0.1:3000", Some("GOOGLE"))
+        ));
+        assert!(
+            !selection.are_related(&named("10.0.0.1:3000", None), &named("10.1.0.1:3000", None))
+        );
+    }
+
+    #[test]
+    fn related_mutual_family() {
+        let selection = PathSelection::for_test(true);
+
+        let mut a = NodeUnselected::for_test("10.0.0.1:3000");
+        let mut b = NodeUnselected::for_test("10.1.0.1:3000");
+        let mut c = NodeUnselected::for_test("10.2.0.1:3000");
+
+        a.family.push(b.fingerprint.clone());
+        b.family.push(a.fingerprint.clone());
+        c.family.push(a.fingerprint.clone());
+
+        assert!(selection.are_related(&a, &b));
+        assert!(selection.are_related(&b, &a));
+        // only c declares a, so anybody could push a off circuits otherwise
+        assert!(!selection.are_related(&a, &c));
+        assert!(!selection.are_related(&c, &a));
+    }
+
+    #[test]
+    fn weighted_clamp_to_median() {
+        let nodes = [
+            node("10.0.0.1:3000", Some(100), None),
+            node("10.1.0.1:3000", Some(100), None),
+            node("10.2.0.1:3000", Some(100), None),
+            node("10.3.0.1:3000", Some(1), None),
+            node("10.4.0.1:3000", Some(1_000_000), None),
+        ];
+
+        assert_eq!(
+            weights(&nodes),
+            [
+                100.0,
+                100.0,
+                100.0,
+                100.0 / MAX_WEIGHT_FACTOR,
+                100.0 * MAX_WEIGHT_FACTOR
+            ]
+        );
+    }
+
+    #[test]
+    fn weighted_discount_rtt() {
+        let nodes = [
+            node("10.0.0.1:3000", Some(100), Some(0)),
+            node("10.1.0.1:3000", Some(100), Some(RTT_HALVING_MS as u64)),
+        ];
+
+        assert_eq!(weights(&nodes), [100.0, 50.0]);
+    }
+
+    #[test]
+    fn weighted_unknown_as_median() {
+        let nodes = [
+            node("10.0.0.1:3000", Some(100), Some(0)),
+            node("10.1.0.1:3000", Some(200), Some(100)),
+            node("10.2.0.1:3000", Some(300), Some(200)),
+            node("10.3.0.1:3000", None, None),
+        ];
+
+        let weighted = weights(&nodes);
+
+        assert_eq!(weighted[3], weighted[1]);
+
+        // nothing advertised or measured at all
+        let nodes = [
+            node("10.0.0.1:3000", None, None),
+            node("10.1.0.1:3000", None, None),
+        ];
+
+        assert_eq!(weights(&nodes), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn choose_distinct_candidates() {
+        let nodes = [
+            node("10.0.0.1:3000", None, None),
+            node("10.1.0.1:3000", None, None),
+            node("10.2.0.1:3000", None, None),
+        ];
+        let candidates: Vec<&NodeUnselected> = nodes.iter().collect();
+
+        let mut chosen: Vec<std::net::SocketAddr> = choose(&Weighted, &candidates, 3)
+            .unwrap()
+            .iter()
+            .map(|n| n.addr)
+            .collect();
+        chosen.sort();
+        chosen.dedup();
+
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(choose(&Weighted, &candidates, 5).unwrap().len(), 3);
+        assert!(choose(&Weighted, &[], 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn choose_reject_invalid_weights() {
+        let nodes = [
+            node("10.0.0.1:3000", None, None),
+            node("10.1.0.1:3000", None, None),
+        ];
+        let candidates: Vec<&NodeUnselected> = nodes.iter().collect();
+
+        for weights in [
+            vec![1.0, f64::NAN],
+            vec![1.0, f64::INFINITY],
+            vec![1.0, 0.0],
+            vec![1.0, -1.0],
+            vec![1.0],
+        ] {
+            assert!(choose(&Fixed(weights), &candidates, 1).is_err());
+        }
+
+        assert!(choose(&Fixed(vec![1.0, 2.0]), &candidates, 1).is_ok());
+    }
+}
@@ -0,0 +1,386 @@
+use crate::auth::{Auth, User};
+use crate::hint::Hints;
+use anyhow::{bail, Result};
+use negy_common::destination::Destination;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// RFC 1928 (CONNECT only) with RFC 1929 username/password authentication.
+pub const VERSION: u8 = 5;
+
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const AUTH_VERSION: u8 = 1;
+const AUTH_SUCCEEDED: u8 = 0;
+const AUTH_FAILED: u8 = 1;
+
+const CONNECT: u8 = 1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+#[derive(Debug, Copy, Clone)]
+pub enum ReplyCode {
+    Succeeded = 0,
+    GeneralFailure = 1,
+    NotAllowed = 2,
+    HostUnreachable = 4,
+    TtlExpired = 6,
+    CommandNotSupported = 7,
+    AddressTypeNotSupported = 8,
+}
+
+// Negotiates until the CONNECT request and returns its destination with the authenticated user
+// and the hints appended to the username. The reply is sent by the caller once the stream is open.
+pub async fn accept<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    auth: &Auth,
+) -> Result<(Destination, Option<User>, Hints)> {
+    let version = client.read_u8().await?;
+
+    if version != VERSION {
+        bail!("unsupported SOCKS version {}", version)
+    }
+
+    let methods_len = client.read_u8().await? as usize;
+    let mut methods = vec![0; methods_len];
+    client.read_exact(&mut methods).await?;
+
+    // same credentials as the Proxy-Authorization header.
+    // clients offering a username are asked for it even without auth, as it may carry hints.
+    let method = if auth.is_required()
+        || methods.contains(&USERNAME_PASSWORD)
+        || !methods.contains(&NO_AUTH)
+    {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+
+    if !methods.contains(&method) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        bail!("authorization required")
+    }
+
+    client.write_all(&[VERSION, method]).await?;
+
+    let (user, hints) = if method == USERNAME_PASSWORD {
+        authenticate(client, auth).await?
+    } else {
+        (None, Hints::default())
+    };
+
+    let mut header = [0; 4];
+    client.read_exact(&mut header).await?;
+
+    if header[0] != VERSION {
+        bail!("unsupported SOCKS version {}", header[0])
+    }
+
+    if header[1] != CONNECT {
+        reply(client, ReplyCode::CommandNotSupported).await?;
+        bail!(
+            "unsupported SOCKS command {}. Only CONNECT is supported.",
+            header[1]
+        )
+    }
+
+    let dist = match header[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            client.read_exact(&mut ip).await?;
+
+            Destination::new(&Ipv4Addr::from(ip).to_string(), client.read_u16().await?)?
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            client.read_exact(&mut ip).await?;
+
+            Destination::new(&Ipv6Addr::from(ip).to_string(), client.read_u16().await?)?
+        }
+        ATYP_DOMAIN => {
+            let domain_len = client.read_u8().await? as usize;
+            let mut domain = vec![0; domain_len];
+            client.read_exact(&mut domain).await?;
+
+            // resolved by the exit
+            Destination::new(&String::from_utf8(domain)?, client.read_u16().await?)?
+        }
+        atyp => {
+            reply(client, ReplyCode::AddressTypeNotSupported).await?;
+            bail!("unsupported SOCKS address type {}", atyp)
+        }
+    };
+
+    Ok((dist, user, hints))
+}
+
+async fn authenticate<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    auth: &Auth,
+) -> Result<(Option<User>, Hints)> {
+    let version = client.read_u8().await?;
+
+    if version != AUTH_VERSION {
+        bail!("unsupported SOCKS authentication version {}", version)
+    }
+
+    let username_len = client.read_u8().await? as usize;
+    let mut username = vec![0; username_len];
+    client.read_exact(&mut username).await?;
+
+    let password_len = client.read_u8().await? as usize;
+    let mut password = vec![0; password_len];
+    client.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+
+    let password = String::from_utf8_lossy(&password);
+
+    let authenticated = match Hints::split_username(&username) {
+        Ok((username, hints)) => auth
+            .authenticate(username, &password)
+            .await
+            .map(|user| (user, hints)),
+        Err(e) => Err(e),
+    };
+
+    let (user, hints) = match authenticated {
+        Ok(authenticated) => authenticated,
+        Err(e) => {
+            client.write_all(&[AUTH_VERSION, AUTH_FAILED]).await?;
+            return Err(e);
+        }
+    };
+
+    client.write_all(&[AUTH_VERSION, AUTH_SUCCEEDED]).await?;
+
+    Ok((user, hints))
+}
+
+// The bound address isn't meaningful behind a circuit, so it's always 0.0.0.0:0.
+pub async fn reply<C: AsyncWrite + Unpin>(client: &mut C, code: ReplyCode) -> Result<()> {
+    client
+        .write_all(&[VERSION, code as u8, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::NodeMatcher;
+    use tokio::io::duplex;
+
+    fn open() -> Auth {
+        Auth {
+            token: None,
+            credentials: None,
+            realm: "negy".to_owned(),
+        }
+    }
+
+    fn token(token: &str) -> Auth {
+        Auth {
+            token: Some(token.to_owned()),
+            ..open()
+        }
+    }
+
+    fn username_password(username: &str, password: &str) -> Vec<u8> {
+        let mut bytes = vec![AUTH_VERSION, username.len() as u8];
+        bytes.extend_from_slice(username.as_bytes());
+        bytes.push(password.len() as u8);
+        bytes.extend_from_slice(password.as_bytes());
+        bytes
+    }
+
+    // Sends everything the client would send up front, and returns the result with what the gateway wrote back.
+    async fn negotiate(
+        auth: &Auth,
+        request: &[&[u8]],
+    ) -> (Result<(Destination, Option<User>, Hints)>, Vec<u8>) {
+        let (mut client, mut server) = duplex(4096);
+
+        client.write_all(&request.concat()).await.unwrap();
+
+        let accepted = accept(&mut server, auth).await;
+        drop(server);
+
+        let mut written = Vec::new();
+        client.read_to_end(&mut written).await.unwrap();
+
+        (accepted, written)
+    }
+
+    const CONNECT_IPV4: &[u8] = &[VERSION, CONNECT, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80];
+
+    #[tokio::test]
+    async fn socks5_no_auth_ipv4() {
+        let (accepted, written) = negotiate(&open(), &[&[VERSION, 1, NO_AUTH], CONNECT_IPV4]).await;
+        let (dist, user, hints) = accepted.unwrap();
+
+        assert_eq!(written, [VERSION, NO_AUTH]);
+        assert_eq!((dist.host.as_str(), dist.port), ("1.2.3.4", 80));
+        assert!(user.is_none());
+        assert!(hints.exit_node.is_none());
+    }
+
+    #[tokio::test]
+    async fn socks5_domain() {
+        let mut request = vec![VERSION, CONNECT, 0, ATYP_DOMAIN, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+
+        let (accepted, _) = negotiate(&open(), &[&[VERSION, 1, NO_AUTH], &request]).await;
+        let (dist, _, _) = accepted.unwrap();
+
+        assert_eq!((dist.host.as_str(), dist.port), ("example.com", 443));
+    }
+
+    #[tokio::test]
+    async fn socks5_ipv6() {
+        let mut request = vec![VERSION, CONNECT, 0, ATYP_IPV6];
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&8080u16.to_be_bytes());
+
+        let (accepted, _) = negotiate(&open(), &[&[VERSION, 1, NO_AUTH], &request]).await;
+        let (dist, _, _) = accepted.unwrap();
+
+        assert_eq!((dist.host.as_str(), dist.port), ("::1", 8080));
+    }
+
+    #[tokio::test]
+    async fn socks5_username_offered_without_auth() {
+        let (accepted, written) = negotiate(
+            &open(),
+            &[
+                &[VERSION, 2, NO_AUTH, USERNAME_PASSWORD],
+                &username_password("alice+exit=country:JP", ""),
+                CONNECT_IPV4,
+            ],
+        )
+        .await;
+        let (_, user, hints) = accepted.unwrap();
+
+        assert_eq!(
+            written,
+            [VERSION, USERNAME_PASSWORD, AUTH_VERSION, AUTH_SUCCEEDED]
+        );
+        assert!(user.is_none());
+        assert_eq!(hints.exit_node, Some(NodeMatcher::Country("JP".to_owned())));
+    }
+
+    #[tokio::test]
+    async fn socks5_token_with_hints() {
+        let (accepted, written) = negotiate(
+            &token("secret"),
+            &[
+                &[VERSION, 1, USERNAME_PASSWORD],
+                &username_password("secret+hops=2", "anything"),
+                CONNECT_IPV4,
+            ],
+        )
+        .await;
+        let (_, user, hints) = accepted.unwrap();
+
+        assert_eq!(
+            written,
+            [VERSION, USERNAME_PASSWORD, AUTH_VERSION, AUTH_SUCCEEDED]
+        );
+        assert!(user.is_none());
+        assert_eq!(hints.hops, Some(2));
+    }
+
+    #[tokio::test]
+    async fn socks5_reject_wrong_token() {
+        let (accepted, written) = negotiate(
+            &token("secret"),
+            &[
+                &[VERSION, 1, USERNAME_PASSWORD],
+                &username_password("guess", ""),
+            ],
+        )
+        .await;
+
+        assert!(accepted.is_err());
+        assert_eq!(
+            written,
+            [VERSION, USERNAME_PASSWORD, AUTH_VERSION, AUTH_FAILED]
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_reject_no_auth_when_required() {
+        let (accepted, written) = negotiate(&token("secret"), &[&[VERSION, 1, NO_AUTH]]).await;
+
+        assert!(accepted.is_err());
+        assert_eq!(written, [VERSION, NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[tokio::test]
+    async fn socks5_reject_unsupported_version() {
+        let (accepted, written) = negotiate(&open(), &[&[4, 1, NO_AUTH]]).await;
+
+        assert!(accepted.is_err());
+        assert!(written.is_empty());
+
+        let (accepted, _) = negotiate(
+            &open(),
+            &[
+                &[VERSION, 1, NO_AUTH],
+                &[4, CONNECT, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80],
+            ],
+        )
+        .await;
+
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn socks5_reject_unsupported_address_type() {
+        let (accepted, written) = negotiate(
+            &open(),
+            &[&[VERSION, 1, NO_AUTH], &[VERSION, CONNECT, 0, 2, 0, 0]],
+        )
+        .await;
+
+        assert!(accepted.is_err());
+        assert_eq!(
+            written,
+            [
+                VERSION,
+                NO_AUTH,
+                VERSION,
+                ReplyCode::AddressTypeNotSupported as u8,
+                0,
+                ATYP_IPV4,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_reject_unsupported_command() {
+        let (accepted, written) = negotiate(
+            &open(),
+            &[
+                &[VERSION, 1, NO_AUTH],
+                &[VERSION, 2, 0, ATYP_IPV4, 1, 2, 3, 4, 0, 80],
+            ],
+        )
+        .await;
+
+        assert!(accepted.is_err());
+        assert_eq!(written[3], ReplyCode::CommandNotSupported as u8);
+    }
+}
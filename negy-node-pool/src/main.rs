@@ -3,12 +3,14 @@ extern crate log;
 
 use anyhow::{bail, Result};
 use clap::Parser;
+use negy_common::policy::Policy;
 use negy_common::protocol::Protocol;
-use negy_node_pool::req::{AddNodeRequest, ListNodeResponse, ListedNode};
+use negy_node_pool::req::{AddNodeRequest, ListNodeResponse, ListedNode, NodeRole};
 use openssl::rsa::Rsa;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use warp::Filter;
@@ -27,6 +29,13 @@ struct Node {
     public_key: String,
     version: String,
     name: Option<String>,
+    country: Option<String>,
+    protocol_version: Option<u8>,
+    exit_policy: Option<Vec<String>>,
+    role: NodeRole,
+    bandwidth: Option<u64>,
+    rtt: Duration,
+    family: Option<Vec<String>>,
 }
 
 #[derive(Debug)]
@@ -34,11 +43,15 @@ struct InvalidParameters;
 
 impl warp::reject::Reject for InvalidParameters {}
 
-async fn loopup_name(ip: &IpAddr) -> Result<Option<String>> {
+// RDAP network name and country code of the address
+async fn lookup_network(ip: &IpAddr) -> Result<(Option<String>, Option<String>)> {
     let url = format!("https://rdap.apnic.net/ip/{}", ip);
     let res = reqwest::get(url).await?.json::<serde_json::Value>().await?;
 
-    Ok(res["name"].as_str().map(|n| n.to_owned()))
+    Ok((
+        res["name"].as_str().map(|n| n.to_owned()),
+        res["country"].as_str().map(|c| c.to_uppercase()),
+    ))
 }
 
 async fn list(
@@ -52,6 +65,13 @@ async fn list(
             public_key: node.public_key,
             version: node.version,
             name: node.name,
+            country: node.country,
+            protocol_version: node.protocol_version,
+            exit_policy: node.exit_policy,
+            role: Some(node.role),
+            bandwidth: node.bandwidth,
+            rtt_ms: Some(node.rtt.as_millis() as u64),
+            family: node.family,
         })
         .collect();
 
@@ -77,7 +97,7 @@ async fn add(
 
     info!("new add request {}", addr);
 
-    let name = loopup_name(&addr.ip())
+    let (name, country) = lookup_network(&addr.ip())
         .await
         .map_err(|_| warp::reject::custom(InvalidParameters))?;
 
@@ -88,19 +108,37 @@ async fn add(
     Rsa::public_key_from_pem(&public_key_bytes)
         .map_err(|_| warp::reject::custom(InvalidParameters))?;
 
-    if healthcheck_node(&addr, &body.public_key, &body.version)
-        .await
-        .is_ok()
-    {
+    if let Some(exit_policy) = &body.exit_policy {
+        Policy::parse(exit_policy).map_err(|_| warp::reject::custom(InvalidParameters))?;
+    }
+
+    if let Some(family) = &body.family {
+        if !family.iter().all(|f| is_fingerprint(f)) {
+            return Err(warp::reject::custom(InvalidParameters));
+        }
+    }
+
+    if let Ok(rtt) = healthcheck_node(&addr, &body.public_key, &body.version).await {
         node_pool.write().unwrap().insert(
             addr,
             Node {
                 public_key: body.public_key,
                 version: body.version,
                 name,
+                country,
+                protocol_version: body.protocol_version,
+                exit_policy: body.exit_policy,
+                role: body.role.unwrap_or_default(),
+                bandwidth: body.bandwidth,
+                rtt,
+                family: body.family,
             },
         );
-        info!("new node has been added {}", addr);
+        info!(
+            "new node has been added {} ({})",
+            addr,
+            body.role.unwrap_or_default()
+        );
     } else {
         warn!(
             "cannot connect to the node. may be it's not public ip {}",
@@ -111,11 +149,18 @@ async fn add(
     Ok(warp::reply::with_status("ok", warp::http::StatusCode::OK))
 }
 
+// hex SHA-256 of a node's public key
+fn is_fingerprint(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 async fn pong() -> Result<impl warp::Reply, warp::Rejection> {
     Ok(warp::reply::with_status("ok", warp::http::StatusCode::OK))
 }
 
-async fn healthcheck_node(addr: &SocketAddr, public_key: &str, version: &str) -> Result<()> {
+// Returns the round trip time from connecting until the node context is received.
+async fn healthcheck_node(addr: &SocketAddr, public_key: &str, version: &str) -> Result<Duration> {
+    let started_at = Instant::now();
     let mut node = TcpStream::connect(addr).await?;
     let mut bytes = [0; 1024];
     let (mut rx, mut tx) = node.split();
@@ -123,8 +168,15 @@ async fn healthcheck_node(addr: &SocketAddr, public_key: &str, version: &str) ->
     tx.write_u8(Protocol::NodeContext.symbol_byte()).await?;
 
     let n = rx.read(&mut bytes).await?;
-    let public_key_received = base64::encode(&bytes[..451]);
-    let version_received = std::str::from_utf8(&bytes[451..n])?;
+    let rtt = started_at.elapsed();
+    let public_key_len = base64::decode(public_key)?.len();
+
+    if n < public_key_len {
+        bail!("node context is too short")
+    }
+
+    let public_key_received = base64::encode(&bytes[..public_key_len]);
+    let version_received = std::str::from_utf8(&bytes[public_key_len..n])?;
 
     if public_key_received != public_key {
         bail!("public key mismatch")
@@ -134,7 +186,7 @@ async fn healthcheck_node(addr: &SocketAddr, public_key: &str, version: &str) ->
         bail!("version mismatch ({} vs {})", version, version_received)
     }
 
-    Ok(())
+    Ok(rtt)
 }
 
 async fn healthcheck_loop(node_pool: Arc<RwLock<HashMap<SocketAddr, Node>>>) -> Result<()> {
@@ -143,10 +195,18 @@ async fn healthcheck_loop(node_pool: Arc<RwLock<HashMap<SocketAddr, Node>>>) ->
         let node_pool_for_iter = node_pool.read().unwrap().clone();
 
         for (addr, node) in node_pool_for_iter.iter() {
-            if let Err(e) = healthcheck_node(addr, &node.public_key, &node.version).await {
-                warn!("removing reason={:?}", e);
-                node_pool.write().unwrap().remove(addr);
-                removed_count += 1;
+            match healthcheck_node(addr, &node.public_key, &node.version).await {
+                Ok(rtt) => {
+                    if let Some(node) = node_pool.write().unwrap().get_mut(addr) {
+                        // moving average, so a single slow check doesn't stand out
+                        node.rtt = (node.rtt * 4 + rtt) / 5;
+                    }
+                }
+                Err(e) => {
+                    warn!("removing reason={:?}", e);
+                    node_pool.write().unwrap().remove(addr);
+                    removed_count += 1;
+                }
             }
         }
 
@@ -154,13 +214,13 @@ async fn healthcheck_loop(node_pool: Arc<RwLock<HashMap<SocketAddr, Node>>>) ->
             info!("removed {} nodes", removed_count);
         }
 
-        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
 
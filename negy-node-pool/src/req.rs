@@ -1,11 +1,69 @@
 use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 use std::net::SocketAddr;
+use std::str::FromStr;
+
+// Which hops a node serves. Nodes which announce no role are relays.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeRole {
+    #[default]
+    Relay,
+    Exit,
+    Both,
+}
+
+impl NodeRole {
+    pub fn is_relay(&self) -> bool {
+        *self != NodeRole::Exit
+    }
+
+    pub fn is_exit(&self) -> bool {
+        *self != NodeRole::Relay
+    }
+}
+
+impl FromStr for NodeRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relay" => Ok(NodeRole::Relay),
+            "exit" => Ok(NodeRole::Exit),
+            "both" => Ok(NodeRole::Both),
+            _ => Err(format!("unknown role {:?} (relay, exit or both)", s)),
+        }
+    }
+}
+
+impl Display for NodeRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeRole::Relay => write!(f, "relay"),
+            NodeRole::Exit => write!(f, "exit"),
+            NodeRole::Both => write!(f, "both"),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddNodeRequest {
     pub port: u16,
     pub public_key: String,
     pub version: String,
+    #[serde(default)]
+    pub protocol_version: Option<u8>,
+    // policy rules of destinations the node connects to as an exit
+    #[serde(default)]
+    pub exit_policy: Option<Vec<String>>,
+    #[serde(default)]
+    pub role: Option<NodeRole>,
+    // capacity declared by the operator, in kilobytes per second
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    // fingerprints of other nodes run by the same operator, which never share a circuit with this one
+    #[serde(default)]
+    pub family: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,6 +72,21 @@ pub struct ListedNode {
     pub public_key: String,
     pub version: String,
     pub name: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub protocol_version: Option<u8>,
+    #[serde(default)]
+    pub exit_policy: Option<Vec<String>>,
+    #[serde(default)]
+    pub role: Option<NodeRole>,
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    // round trip time of health checks from the node pool, smoothed
+    #[serde(default)]
+    pub rtt_ms: Option<u64>,
+    #[serde(default)]
+    pub family: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
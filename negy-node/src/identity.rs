@@ -0,0 +1,182 @@
+use anyhow::{bail, Result};
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const RSA_BITS: u32 = 2048;
+
+pub fn load_or_create(path: &Path) -> Result<Rsa<Private>> {
+    if !path.exists() {
+        info!("identity key not found. creating a new one at {:?}", path);
+
+        let rsa = Rsa::generate(RSA_BITS)?;
+        write(path, &rsa)?;
+
+        return Ok(rsa);
+    }
+
+    check_permissions(path)?;
+
+    let rsa = Rsa::private_key_from_pem(&fs::read(path)?)?;
+
+    if !rsa.check_key()? {
+        bail!("identity key at {:?} is broken", path)
+    }
+
+    info!("loaded identity key from {:?}", path);
+
+    Ok(rsa)
+}
+
+pub fn rotate(path: &Path) -> Result<Rsa<Private>> {
+    let rsa = Rsa::generate(RSA_BITS)?;
+
+    if path.exists() {
+        let backup = with_extension(path, "old");
+
+        fs::copy(path, &backup)?;
+        info!("previous identity key was backed up to {:?}", backup);
+    }
+
+    write(path, &rsa)?;
+
+    Ok(rsa)
+}
+
+// Writes to a temporary file first so a crash never leaves a truncated key behind.
+// A leftover temporary file is removed, as it would keep its permissions.
+fn write(path: &Path, rsa: &Rsa<Private>) -> Result<()> {
+    let tmp = with_extension(path, "tmp");
+
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+
+    let mut options = OpenOptions::new();
+
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(&rsa.private_key_to_pem()?)?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+
+    if mode & 0o077 != 0 {
+        bail!(
+            "permissions {:o} for identity key {:?} are too open. run `chmod 600` on it.",
+            mode & 0o777,
+            path
+        )
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(extension);
+
+    PathBuf::from(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.pem");
+
+        let created = load_or_create(&path).unwrap();
+        let loaded = load_or_create(&path).unwrap();
+
+        assert_eq!(
+            created.private_key_to_pem().unwrap(),
+            loaded.private_key_to_pem().unwrap()
+        );
+    }
+
+    #[test]
+    fn identity_rotate_keeps_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.pem");
+
+        let previous = load_or_create(&path).unwrap();
+        let rotated = rotate(&path).unwrap();
+
+        assert_ne!(
+            previous.private_key_to_pem().unwrap(),
+            rotated.private_key_to_pem().unwrap()
+        );
+        assert_eq!(
+            fs::read(with_extension(&path, "old")).unwrap(),
+            previous.private_key_to_pem().unwrap()
+        );
+        assert_eq!(
+            load_or_create(&path).unwrap().private_key_to_pem().unwrap(),
+            rotated.private_key_to_pem().unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_refuse_open_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.pem");
+
+        load_or_create(&path).unwrap();
+
+        for mode in [0o640, 0o604] {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            assert!(load_or_create(&path).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_leftover_tmp_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.pem");
+        let tmp = with_extension(&path, "tmp");
+
+        fs::write(&tmp, "leftover").unwrap();
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o644)).unwrap();
+
+        load_or_create(&path).unwrap();
+
+        assert!(!tmp.exists());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+}
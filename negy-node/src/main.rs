@@ -1,16 +1,24 @@
 #[macro_use]
 extern crate log;
 
+mod identity;
 mod node;
 
-use crate::node::Node;
-use anyhow::{bail, Result};
-use clap::Parser;
-use negy_common::protocol::Protocol;
-use negy_node_pool::req::AddNodeRequest;
+use crate::node::{Node, Service};
+use anyhow::{anyhow, bail, Result};
+use clap::{Parser, Subcommand};
+use negy_common::key_exchange;
+use negy_common::policy::Policy;
+use negy_common::protocol::{Protocol, PROTOCOL_VERSION};
+use negy_node_pool::req::{AddNodeRequest, NodeRole};
 use openssl::{pkey::Private, rsa::Rsa};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 
+// Evaluated after --exit-policy rules.
+const DEFAULT_EXIT_POLICY: [&str; 1] = ["reject private"];
+
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
@@ -20,10 +28,36 @@ struct Args {
     port: u16,
     #[clap(short, long, value_parser, default_value = "http://127.0.0.1:3030")]
     node_pool_endpoint: String,
+    /// PEM file of the node's RSA identity key, created when missing.
+    /// Without it the node gets a new identity and fingerprint on every start.
+    #[clap(long, value_parser)]
+    identity_key: Option<PathBuf>,
+    /// Exits connect to destinations on behalf of clients. Opt in with `exit` or `both`.
+    #[clap(long, value_parser, default_value = "relay")]
+    role: NodeRole,
+    /// Destination rule like `reject *:25` or `accept 10.0.0.0/8:443` for streams exiting here.
+    /// Can be repeated. Private addresses are rejected unless accepted explicitly.
+    #[clap(long, value_parser)]
+    exit_policy: Vec<String>,
+    /// Kilobytes per second this node can relay. Gateways pick faster nodes more often.
+    #[clap(long, value_parser)]
+    bandwidth: Option<u64>,
+    /// Fingerprint of another node you run. Can be repeated.
+    /// Gateways keep two nodes off one circuit when both declare each other.
+    #[clap(long, value_parser)]
+    family: Vec<String>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Replace the key at --identity-key with a newly generated one
+    RotateIdentityKey,
 }
 
-async fn spawn_inner(client: TcpStream, rsa: Rsa<Private>) -> Result<()> {
-    let node = Node::new(client, rsa).accept().await?;
+async fn spawn_inner(client: TcpStream, rsa: Rsa<Private>, service: Arc<Service>) -> Result<()> {
+    let node = Node::new(client, rsa, service).accept().await?;
 
     match node.protocol() {
         Protocol::Tunnel => node.handshake().await?.tunnel().await?,
@@ -33,7 +67,12 @@ async fn spawn_inner(client: TcpStream, rsa: Rsa<Private>) -> Result<()> {
     Ok(())
 }
 
-async fn add_request(rsa: &Rsa<Private>, port: u16, node_pool_endpoint: &str) -> Result<()> {
+async fn add_request(
+    rsa: &Rsa<Private>,
+    port: u16,
+    node_pool_endpoint: &str,
+    service: &Service,
+) -> Result<()> {
     info!("send add/update request to node pool");
 
     let version: &str = env!("CARGO_PKG_VERSION");
@@ -42,6 +81,26 @@ async fn add_request(rsa: &Rsa<Private>, port: u16, node_pool_endpoint: &str) ->
         port,
         public_key: base64::encode(rsa.public_key_to_pem().unwrap()),
         version: version.to_owned(),
+        protocol_version: Some(PROTOCOL_VERSION),
+        exit_policy: if service.role.is_exit() {
+            Some(
+                service
+                    .exit_policy
+                    .rules()
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect(),
+            )
+        } else {
+            None
+        },
+        role: Some(service.role),
+        bandwidth: service.bandwidth,
+        family: if service.family.is_empty() {
+            None
+        } else {
+            Some(service.family.clone())
+        },
     };
     let res = reqwest::Client::new()
         .post(format!("{}/add", node_pool_endpoint))
@@ -65,9 +124,10 @@ async fn connect_to_node_pool(
     rsa: Rsa<Private>,
     port: u16,
     node_pool_endpoint: String,
+    service: Arc<Service>,
 ) -> Result<()> {
     loop {
-        if let Err(e) = add_request(&rsa, port, &node_pool_endpoint).await {
+        if let Err(e) = add_request(&rsa, port, &node_pool_endpoint, &service).await {
             error!("failed to add this node to node pool");
             error!("{:?}", e);
         }
@@ -76,13 +136,24 @@ async fn connect_to_node_pool(
     }
 }
 
-async fn spawn(listener: TcpListener, port: u16, node_pool_endpoint: String) -> Result<()> {
-    let rsa = Rsa::generate(2048)?;
+async fn spawn(
+    listener: TcpListener,
+    rsa: Rsa<Private>,
+    port: u16,
+    node_pool_endpoint: String,
+    service: Arc<Service>,
+) -> Result<()> {
     let rsa_node_pool_connection = rsa.clone();
+    let service_node_pool_connection = service.clone();
 
     tokio::spawn(async move {
-        if let Err(e) =
-            connect_to_node_pool(rsa_node_pool_connection, port, node_pool_endpoint).await
+        if let Err(e) = connect_to_node_pool(
+            rsa_node_pool_connection,
+            port,
+            node_pool_endpoint,
+            service_node_pool_connection,
+        )
+        .await
         {
             error!("{:?}", e);
         }
@@ -91,9 +162,10 @@ async fn spawn(listener: TcpListener, port: u16, node_pool_endpoint: String) ->
     loop {
         let (client, _) = listener.accept().await?;
         let rsa = rsa.clone();
+        let service = service.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = spawn_inner(client, rsa).await {
+            if let Err(e) = spawn_inner(client, rsa, service).await {
                 error!("{:?}", e);
             }
         });
@@ -102,7 +174,7 @@ async fn spawn(listener: TcpListener, port: u16, node_pool_endpoint: String) ->
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
 
@@ -114,13 +186,59 @@ async fn main() -> Result<()> {
     })?;
 
     let args = Args::parse();
+
+    if let Some(Command::RotateIdentityKey) = args.command {
+        let identity_key = args.identity_key.ok_or(anyhow!(
+            "--identity-key is required to rotate the identity key"
+        ))?;
+
+        identity::rotate(&identity_key)?;
+        info!(
+            "rotated identity key at {:?}. restart the node to use it.",
+            identity_key
+        );
+
+        return Ok(());
+    }
+
+    let rsa = if let Some(identity_key) = &args.identity_key {
+        identity::load_or_create(identity_key)?
+    } else {
+        warn!("--identity-key is not specified. this node gets a new identity on every start.");
+        Rsa::generate(2048)?
+    };
+
+    let exit_policy = Policy::parse(
+        &[
+            &args.exit_policy[..],
+            &DEFAULT_EXIT_POLICY.map(String::from)[..],
+        ]
+        .concat(),
+    )?;
+
+    info!("identity fingerprint {}", key_exchange::fingerprint(&rsa)?);
+    info!("serving as {}", args.role);
+
+    if args.role.is_exit() {
+        for rule in exit_policy.rules() {
+            info!("exit policy: {}", rule);
+        }
+    }
+
+    let service = Arc::new(Service {
+        role: args.role,
+        exit_policy,
+        bandwidth: args.bandwidth,
+        family: args.family,
+    });
+
     let bind_addr = format!("{}:{}", args.bind, args.port);
 
     info!("start listening on {}", bind_addr);
 
     let listener = TcpListener::bind(bind_addr).await?;
 
-    spawn(listener, args.port, args.node_pool_endpoint).await?;
+    spawn(listener, rsa, args.port, args.node_pool_endpoint, service).await?;
 
     Ok(())
 }
@@ -1,30 +1,56 @@
-use anyhow::{anyhow, bail, Result};
-use bytes::BytesMut;
-use negy_common::aes::Aes;
-use negy_common::encrypted_payload::{EncryptedPayload, DELIMITER_LEN};
-use negy_common::protocol::{Protocol, PROTOCOL_SYMBOL_LEN};
+use anyhow::{bail, Result};
+use negy_common::aes::{Aes, Direction};
+use negy_common::cell::Cell;
+use negy_common::destination::Destination;
+use negy_common::encrypted_payload::EncryptedPayload;
+use negy_common::key_exchange::{self, KeyExchange, PUBLIC_KEY_LEN};
+use negy_common::oaep;
+use negy_common::policy::Policy;
+use negy_common::protocol::{HandshakeHeader, Protocol, Reply, ReplyStatus};
+use negy_common::stream::{Stream, Streams};
+use negy_node_pool::req::NodeRole;
 use openssl::pkey::Private;
-use openssl::rsa::{Padding, Rsa};
+use openssl::rsa::Rsa;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const UPSTREAM_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Which hops this node serves and where it connects to as an exit.
+pub struct Service {
+    pub role: NodeRole,
+    pub exit_policy: Policy,
+    // advertised to the node pool, in kilobytes per second
+    pub bandwidth: Option<u64>,
+    pub family: Vec<String>,
+}
 
 pub struct StateInit {
     rsa: Rsa<Private>,
     client: TcpStream,
+    service: Arc<Service>,
 }
 
 pub struct StateAccepted {
     rsa: Rsa<Private>,
     protocol: Protocol,
     client: TcpStream,
-    payload_init: BytesMut,
+    service: Arc<Service>,
 }
 
 pub struct StateTunnel {
     aes: Aes,
-    delimiter: [u8; DELIMITER_LEN],
     client: TcpStream,
-    upstream: TcpStream,
+    // None when this node is the exit
+    upstream: Option<TcpStream>,
+    service: Arc<Service>,
 }
 
 pub struct Node<State> {
@@ -32,27 +58,28 @@ pub struct Node<State> {
 }
 
 impl Node<StateInit> {
-    pub fn new(client: TcpStream, rsa: Rsa<Private>) -> Self {
+    pub fn new(client: TcpStream, rsa: Rsa<Private>, service: Arc<Service>) -> Self {
         Node {
-            state: StateInit { rsa, client },
+            state: StateInit {
+                rsa,
+                client,
+                service,
+            },
         }
     }
 
     pub async fn accept(mut self) -> Result<Node<StateAccepted>> {
-        let mut c_bytes = [0; 4096];
-
         let (mut c_rx, _) = self.state.client.split();
 
-        let n = c_rx.read(&mut c_bytes).await?;
-        let payload_init = BytesMut::from(&c_bytes[..n]);
-        let protocol = Protocol::parse(&payload_init)?;
+        let symbol_byte = c_rx.read_u8().await?;
+        let protocol = Protocol::parse(&[symbol_byte])?;
 
         Ok(Node {
             state: StateAccepted {
                 protocol,
                 rsa: self.state.rsa,
                 client: self.state.client,
-                payload_init,
+                service: self.state.service,
             },
         })
     }
@@ -77,84 +104,203 @@ impl Node<StateAccepted> {
     }
 
     pub async fn handshake(mut self) -> Result<Node<StateTunnel>> {
-        let mut u_bytes = [0; 4096];
+        let (aes, upstream, reply) = match self.establish().await {
+            Ok(established) => established,
+            Err(reply) => {
+                let _ = self.reply(&reply).await;
+                bail!("handshake failed ({:?}): {}", reply.status, reply.message)
+            }
+        };
 
-        let (_, mut c_tx) = self.state.client.split();
+        self.reply(&reply).await?;
+
+        Ok(Node {
+            state: StateTunnel {
+                aes,
+                client: self.state.client,
+                upstream,
+                service: self.state.service,
+            },
+        })
+    }
 
-        let payload_len: usize = PROTOCOL_SYMBOL_LEN + self.state.rsa.size() as usize * 3;
-        let payload_self = &self.state.payload_init[..payload_len];
-        let payload_successor = &self.state.payload_init[payload_len..];
+    // Returns no upstream when this node is the exit, which opens connections per stream instead.
+    async fn establish(&mut self) -> Result<(Aes, Option<TcpStream>, Reply), Reply> {
+        let (mut c_rx, _) = self.state.client.split();
+
+        let (header, payload) = HandshakeHeader::read(&mut c_rx)
+            .await
+            .map_err(failure(ReplyStatus::ProtocolError))?;
 
         let rsa_key_len: usize = self.state.rsa.size() as usize;
-        let mut decrypted_delimiter = vec![0; rsa_key_len];
-        self.state.rsa.private_decrypt(
-            &payload_self[PROTOCOL_SYMBOL_LEN..PROTOCOL_SYMBOL_LEN + rsa_key_len],
-            &mut decrypted_delimiter,
-            Padding::PKCS1,
-        )?;
-
-        let mut delimiter = [0; DELIMITER_LEN];
-        delimiter.copy_from_slice(&decrypted_delimiter[..DELIMITER_LEN]);
-
-        let mut decrypted_dist = vec![0; rsa_key_len];
-        self.state.rsa.private_decrypt(
-            &payload_self[PROTOCOL_SYMBOL_LEN + rsa_key_len..PROTOCOL_SYMBOL_LEN + rsa_key_len * 2],
-            &mut decrypted_dist,
-            Padding::PKCS1,
-        )?;
-
-        let mut decrypted_aes = vec![0; rsa_key_len];
-        self.state.rsa.private_decrypt(
-            &payload_self
-                [PROTOCOL_SYMBOL_LEN + rsa_key_len * 2..PROTOCOL_SYMBOL_LEN + rsa_key_len * 3],
-            &mut decrypted_aes,
-            Padding::PKCS1,
-        )?;
-
-        let mut key_iv = [0; 48];
-        key_iv.copy_from_slice(&decrypted_aes[..48]);
-        let aes = Aes::import(&key_iv);
-
-        let dist = std::str::from_utf8(
-            &decrypted_dist
-                .splitn(2, |b| b == &b'\0')
-                .next()
-                .ok_or(anyhow!("failed to find dist in payload"))?,
-        )?;
-
-        let mut upstream = TcpStream::connect(dist).await?;
-        let (mut u_rx, mut u_tx) = upstream.split();
 
-        if payload_successor.len() > 0 {
-            u_tx.write_all(&payload_successor).await?;
+        if header.hop_header_len != rsa_key_len + PUBLIC_KEY_LEN {
+            return Err(Reply::failure(
+                ReplyStatus::ProtocolError,
+                &format!(
+                    "invalid hop header length {} (expected {})",
+                    header.hop_header_len,
+                    rsa_key_len + PUBLIC_KEY_LEN
+                ),
+            ));
+        }
 
-            let n = u_rx.read(&mut u_bytes).await?;
+        let (payload_self, payload_successor) = payload.split_at(header.hop_header_len);
+
+        let decrypted_dist = oaep::decrypt(&self.state.rsa, &payload_self[..rsa_key_len])
+            .map_err(failure(ReplyStatus::DecryptFailed))?;
+
+        let mut gateway_public_key = [0; PUBLIC_KEY_LEN];
+        gateway_public_key.copy_from_slice(&payload_self[rsa_key_len..]);
+
+        let key_exchange = KeyExchange::new().map_err(failure(ReplyStatus::ProtocolError))?;
+        let node_public_key = key_exchange
+            .public_key()
+            .map_err(failure(ReplyStatus::ProtocolError))?;
+        let identity_der = self
+            .state
+            .rsa
+            .public_key_to_der()
+            .map_err(failure(ReplyStatus::ProtocolError))?;
+        let transcript =
+            key_exchange::transcript(&gateway_public_key, &node_public_key, &identity_der);
+
+        let key = key_exchange
+            .derive(&gateway_public_key, &transcript)
+            .map_err(failure(ReplyStatus::ProtocolError))?;
+        let mut aes = Aes::import(&key, Direction::Backward);
+
+        let dist =
+            std::str::from_utf8(&decrypted_dist).map_err(failure(ReplyStatus::DecryptFailed))?;
+
+        let signature = key_exchange::sign(&self.state.rsa, &transcript)
+            .map_err(failure(ReplyStatus::ProtocolError))?;
+        let mut reply = Reply::ok(&node_public_key, &signature);
+
+        if dist.is_empty() {
+            if !payload_successor.is_empty() {
+                return Err(Reply::failure(
+                    ReplyStatus::ProtocolError,
+                    "exit received a successor",
+                ));
+            }
 
-            if n != 2 && u_bytes[..2] != b"OK"[..] {
-                bail!("invalid response by upstream")
+            if !self.state.service.role.is_exit() {
+                return Err(Reply::failure(
+                    ReplyStatus::PolicyDenied,
+                    "this node is not an exit",
+                ));
             }
+
+            return Ok((aes, None, reply));
         }
 
-        c_tx.write_all("OK".as_bytes()).await?;
+        if !self.state.service.role.is_relay() {
+            return Err(Reply::failure(
+                ReplyStatus::PolicyDenied,
+                "this node is not a relay",
+            ));
+        }
 
-        Ok(Node {
-            state: StateTunnel {
-                aes,
-                delimiter,
-                client: self.state.client,
-                upstream,
-            },
-        })
+        let mut upstream = connect(dist).await?;
+        let (mut u_rx, mut u_tx) = upstream.split();
+
+        if !payload_successor.is_empty() {
+            u_tx.write_all(payload_successor)
+                .await
+                .map_err(failure(ReplyStatus::UpstreamConnectFailed))?;
+
+            let reply_successor = timeout(
+                UPSTREAM_REPLY_TIMEOUT,
+                EncryptedPayload::read_frame(&mut u_rx),
+            )
+            .await
+            .map_err(failure(ReplyStatus::Timeout))?
+            .map_err(failure(ReplyStatus::UpstreamProtocolError))?;
+
+            // sealed so that only the gateway can read which hops follow
+            reply.successor = aes
+                .encrypt(&reply_successor)
+                .map_err(failure(ReplyStatus::ProtocolError))?;
+        }
+
+        Ok((aes, Some(upstream), reply))
     }
+
+    async fn reply(&mut self, reply: &Reply) -> Result<()> {
+        let (_, mut c_tx) = self.state.client.split();
+
+        c_tx.write_all(&EncryptedPayload::encode(&reply.encode()?)?)
+            .await?;
+
+        Ok(())
+    }
+}
+
+async fn connect(dist: &str) -> Result<TcpStream, Reply> {
+    timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(dist))
+        .await
+        .map_err(failure(ReplyStatus::Timeout))?
+        .map_err(failure(ReplyStatus::UpstreamConnectFailed))
+}
+
+// Every resolved address is checked, and only accepted ones are connected to,
+// so a hostname can't be used to reach what the exit policy rejects.
+async fn connect_exit(dist: &Destination, exit_policy: &Policy) -> Result<TcpStream, Reply> {
+    exit_policy
+        .check(&dist.host, None, dist.port)
+        .map_err(failure(ReplyStatus::PolicyDenied))?;
+
+    let resolved: Vec<SocketAddr> = timeout(
+        UPSTREAM_CONNECT_TIMEOUT,
+        tokio::net::lookup_host((dist.host.as_str(), dist.port)),
+    )
+    .await
+    .map_err(failure(ReplyStatus::Timeout))?
+    .map_err(failure(ReplyStatus::UpstreamConnectFailed))?
+    .collect();
+
+    let mut rejected = None;
+    let mut accepted = Vec::new();
+
+    for addr in resolved {
+        match exit_policy.check(&dist.host, Some(addr.ip()), dist.port) {
+            Ok(()) => accepted.push(addr),
+            Err(reason) => rejected = Some(reason),
+        }
+    }
+
+    if accepted.is_empty() {
+        return Err(Reply::failure(
+            ReplyStatus::PolicyDenied,
+            &rejected.unwrap_or_else(|| format!("{} resolved to no address", dist)),
+        ));
+    }
+
+    timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(&accepted[..]))
+        .await
+        .map_err(failure(ReplyStatus::Timeout))?
+        .map_err(failure(ReplyStatus::UpstreamConnectFailed))
+}
+
+fn failure<E: Display>(status: ReplyStatus) -> impl FnOnce(E) -> Reply {
+    move |e| Reply::failure(status, &e.to_string())
 }
 
 impl Node<StateTunnel> {
-    pub async fn tunnel(&mut self) -> Result<()> {
+    pub async fn tunnel(mut self) -> Result<()> {
+        match self.state.upstream.take() {
+            Some(upstream) => self.relay(upstream).await,
+            None => self.exit().await,
+        }
+    }
+
+    async fn relay(&mut self, mut upstream: TcpStream) -> Result<()> {
         let mut c_bytes = [0; 4096];
         let mut u_bytes = [0; 4096];
 
         let (mut c_rx, mut c_tx) = self.state.client.split();
-        let (mut u_rx, mut u_tx) = self.state.upstream.split();
+        let (mut u_rx, mut u_tx) = upstream.split();
 
         let mut encrypted_payload = EncryptedPayload::new();
 
@@ -164,7 +310,7 @@ impl Node<StateTunnel> {
                     match n {
                         Ok(0) => break,
                         Ok(n) => {
-                            let payloads = encrypted_payload.read(&c_bytes[..n], &self.state.delimiter)?;
+                            let payloads = encrypted_payload.read(&c_bytes[..n])?;
 
                             for payload in payloads {
                                 let decrypted = self.state.aes.decrypt(&payload)?;
@@ -181,8 +327,7 @@ impl Node<StateTunnel> {
                         Ok(0) => break,
                         Ok(n) => {
                             let encrypted = self.state.aes.encrypt(&u_bytes[..n])?;
-                            let mut payload = BytesMut::from(&encrypted as &[u8]);
-                            payload.extend_from_slice(&self.state.delimiter);
+                            let payload = EncryptedPayload::encode(&encrypted)?;
 
                             c_tx.write_all(&payload).await?;
                         },
@@ -196,4 +341,85 @@ impl Node<StateTunnel> {
 
         Ok(())
     }
+
+    // Streams are dropped with `streams` when the circuit ends.
+    async fn exit(&mut self) -> Result<()> {
+        let mut c_bytes = [0; 4096];
+
+        let (mut c_rx, mut c_tx) = self.state.client.split();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let streams = Streams::new();
+        let mut encrypted_payload = EncryptedPayload::new();
+
+        loop {
+            tokio::select! {
+                n = c_rx.read(&mut c_bytes) => {
+                    let n = n?;
+
+                    if n == 0 {
+                        break;
+                    }
+
+                    for payload in encrypted_payload.read(&c_bytes[..n])? {
+                        match Cell::parse(&self.state.aes.decrypt(&payload)?)? {
+                            Cell::Open { stream_id, dist } => {
+                                let stream = streams.register(stream_id, tx.clone())?;
+                                tokio::spawn(open_stream(stream, dist, self.state.service.clone()));
+                            }
+                            cell => streams.dispatch(cell)?,
+                        }
+                    }
+                }
+                Some(cell) = rx.recv() => {
+                    let encrypted = self.state.aes.encrypt(&cell.encode()?)?;
+                    let payload = EncryptedPayload::encode(&encrypted)?;
+
+                    c_tx.write_all(&payload).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Hostnames are resolved here so the gateway never learns what its clients look up.
+async fn open_stream(stream: Stream, dist: String, service: Arc<Service>) {
+    let stream_id = stream.id;
+
+    let connected = match Destination::parse(&dist) {
+        Ok(dist) => connect_exit(&dist, &service.exit_policy).await,
+        Err(e) => Err(Reply::failure(ReplyStatus::ProtocolError, &e.to_string())),
+    };
+
+    let (opened, upstream) = match connected {
+        Ok(upstream) => (
+            Cell::Opened {
+                stream_id,
+                status: ReplyStatus::Ok,
+                message: String::new(),
+                resolved: upstream.peer_addr().ok(),
+            },
+            Some(upstream),
+        ),
+        Err(reply) => (
+            Cell::Opened {
+                stream_id,
+                status: reply.status,
+                message: reply.message,
+                resolved: None,
+            },
+            None,
+        ),
+    };
+    if stream.send(opened).is_err() {
+        return;
+    }
+
+    if let Some(upstream) = upstream {
+        if let Err(e) = stream.pipe(upstream).await {
+            debug!("stream #{} {:?}", stream_id, e);
+        }
+    }
 }